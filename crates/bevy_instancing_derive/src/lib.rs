@@ -0,0 +1,564 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    Attribute, Data, DeriveInput, Error, Expr, Field, Fields, Ident, Lit, Meta, NestedMeta, Result,
+    Token, Type,
+};
+
+const INSTANCE_ATTRIBUTE_NAME: &str = "instance";
+const LOCATION_ATTRIBUTE_NAME: &str = "location";
+const SKIP_ATTRIBUTE_NAME: &str = "skip";
+const POSITION_ATTRIBUTE_NAME: &str = "position";
+const ANIMATION_FRAME_ATTRIBUTE_NAME: &str = "animation_frame";
+const SHADOW_VERTEX_SHADER_ATTRIBUTE_NAME: &str = "shadow_vertex_shader";
+const PREPASS_VERTEX_SHADER_ATTRIBUTE_NAME: &str = "prepass_vertex_shader";
+const IMPOSTOR_VERTEX_SHADER_ATTRIBUTE_NAME: &str = "impostor_vertex_shader";
+const INSTANCE_BOUNDS_WGSL_ATTRIBUTE_NAME: &str = "instance_bounds_wgsl";
+
+/// Implements `InstanceData::buffer_layout` from the fields of a `#[repr(C)]` struct.
+///
+/// Supported field types are `Vec2`, `Vec3`, `Vec4`, `Mat4`, `u32`, `i32`, `f32` and
+/// `[f32; N]` with `N` in `1..=4`. A `Mat4` takes four consecutive `Float32x4` locations.
+///
+/// Fields are assigned consecutive shader locations starting at 0. `#[instance(location = N)]`
+/// moves a field to location `N`, the following fields continue from there. Two fields can't
+/// share a location.
+/// `#[instance(skip)]` keeps a field (e.g. padding) out of the layout.
+///
+/// `#[instance(position)]` on a `Vec3`, `Vec4` or `Mat4` field implements
+/// `InstanceData::position` from it, the translation for a `Mat4`. A `Mat4` also scales
+/// `InstanceData::radius` by its largest axis. `InstanceData::transform` is the field itself for
+/// a `Mat4`, its translation otherwise. `InstanceData::transformed` moves the field, and keeps
+/// the `w` of a `Vec4`.
+///
+/// `#[instance(animation_frame)]` on a `u32` field implements `InstanceData::animation_frame`
+/// from it.
+///
+/// `#[instance(shadow_vertex_shader = ...)]`, `#[instance(prepass_vertex_shader = ...)]` and
+/// `#[instance(impostor_vertex_shader = ...)]` on the struct set the matching `InstanceData`
+/// shaders, either to an asset path string or to a `HandleUntyped` constant of a shader.
+/// `#[instance(instance_bounds_wgsl = ...)]` sets `InstanceData::instance_bounds_wgsl` to a
+/// string literal or constant.
+#[proc_macro_derive(InstanceData, attributes(instance))]
+pub fn derive_instance_data(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    instance_data_impl(ast)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn instance_data_impl(ast: DeriveInput) -> Result<TokenStream2> {
+    let fields = match &ast.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            Fields::Unnamed(fields) => fields.unnamed.iter().collect::<Vec<_>>(),
+            Fields::Unit => {
+                return Err(Error::new_spanned(
+                    &ast,
+                    "InstanceData can't be derived for unit structs",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &ast,
+                "InstanceData can only be derived for structs",
+            ))
+        }
+    };
+
+    let render_resource = quote! { ::bevy::render::render_resource };
+    let mut location = 0u32;
+    let mut used_locations = Vec::new();
+    let mut attributes = Vec::new();
+    let mut bounds = None;
+    let mut animation_frame = None;
+    for (index, field) in fields.into_iter().enumerate() {
+        let ty = &field.ty;
+        let field_attrs = FieldAttrs::parse(field)?;
+        let member = match &field.ident {
+            Some(ident) => quote! { #ident },
+            None => {
+                let index = syn::Index::from(index);
+                quote! { #index }
+            }
+        };
+        if field_attrs.position {
+            if bounds.is_some() {
+                return Err(Error::new_spanned(
+                    field,
+                    "only one field can be marked with #[instance(position)]",
+                ));
+            }
+            bounds = Some(bounds_fns(ty, member.clone())?);
+        }
+        if field_attrs.animation_frame {
+            if animation_frame.is_some() {
+                return Err(Error::new_spanned(
+                    field,
+                    "only one field can be marked with #[instance(animation_frame)]",
+                ));
+            }
+            match ty {
+                Type::Path(path) if path.path.is_ident("u32") => {}
+                _ => {
+                    return Err(Error::new_spanned(
+                        ty,
+                        "#[instance(animation_frame)] expects a u32 field",
+                    ))
+                }
+            }
+            animation_frame = Some(quote! {
+                fn animation_frame(&self) -> ::std::option::Option<u32> {
+                    ::std::option::Option::Some(self.#member)
+                }
+            });
+        }
+        if !field_attrs.skip {
+            if let Some(field_location) = field_attrs.location {
+                location = field_location;
+            }
+            let formats = vertex_formats(ty)?;
+            for (column, format) in formats.into_iter().enumerate() {
+                if used_locations.contains(&location) {
+                    return Err(Error::new_spanned(
+                        field,
+                        format!("shader location {location} is already used by another field"),
+                    ));
+                }
+                used_locations.push(location);
+                let column_offset = column as u64 * 16;
+                attributes.push(quote_spanned! {field.span()=>
+                    attributes.push(#render_resource::VertexAttribute {
+                        format: #render_resource::VertexFormat::#format,
+                        offset: offset + #column_offset,
+                        shader_location: #location,
+                    });
+                });
+                location += 1;
+            }
+        }
+        attributes.push(quote! {
+            offset += ::std::mem::size_of::<#ty>() as u64;
+        });
+    }
+
+    let struct_attrs = StructAttrs::parse(&ast.attrs)?;
+    let shader_fn = |name: &str, shader: Option<Expr>| {
+        let name = Ident::new(name, Span::call_site());
+        shader.map(|shader| {
+            let shader = match shader {
+                Expr::Lit(lit) => quote! { #render_resource::ShaderRef::from(#lit) },
+                handle => quote! {
+                    #render_resource::ShaderRef::from(#handle.typed::<#render_resource::Shader>())
+                },
+            };
+            quote! {
+                fn #name() -> #render_resource::ShaderRef {
+                    #shader
+                }
+            }
+        })
+    };
+    let shadow_vertex_shader = shader_fn(
+        SHADOW_VERTEX_SHADER_ATTRIBUTE_NAME,
+        struct_attrs.shadow_vertex_shader,
+    );
+    let prepass_vertex_shader = shader_fn(
+        PREPASS_VERTEX_SHADER_ATTRIBUTE_NAME,
+        struct_attrs.prepass_vertex_shader,
+    );
+    let impostor_vertex_shader = shader_fn(
+        IMPOSTOR_VERTEX_SHADER_ATTRIBUTE_NAME,
+        struct_attrs.impostor_vertex_shader,
+    );
+    let instance_bounds_wgsl = struct_attrs.instance_bounds_wgsl.map(|wgsl| {
+        quote! {
+            fn instance_bounds_wgsl() -> ::std::option::Option<&'static str> {
+                ::std::option::Option::Some(#wgsl)
+            }
+        }
+    });
+
+    let ident = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::bevy_instancing::InstanceData for #ident #ty_generics #where_clause {
+            fn buffer_layout() -> #render_resource::VertexBufferLayout {
+                let mut attributes = ::std::vec::Vec::new();
+                let mut offset = 0u64;
+                #(#attributes)*
+                #render_resource::VertexBufferLayout {
+                    array_stride: ::std::mem::size_of::<Self>() as u64,
+                    step_mode: #render_resource::VertexStepMode::Instance,
+                    attributes,
+                }
+            }
+
+            #shadow_vertex_shader
+            #prepass_vertex_shader
+            #impostor_vertex_shader
+            #instance_bounds_wgsl
+            #bounds
+            #animation_frame
+        }
+    })
+}
+
+#[derive(Default)]
+struct StructAttrs {
+    shadow_vertex_shader: Option<Expr>,
+    prepass_vertex_shader: Option<Expr>,
+    impostor_vertex_shader: Option<Expr>,
+    instance_bounds_wgsl: Option<Expr>,
+}
+
+impl StructAttrs {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut struct_attrs = StructAttrs::default();
+        for attr in attrs {
+            if !attr.path.is_ident(INSTANCE_ATTRIBUTE_NAME) {
+                continue;
+            }
+            let args =
+                attr.parse_args_with(Punctuated::<NameValueExpr, Token![,]>::parse_terminated)?;
+            for arg in args {
+                if arg.name == SHADOW_VERTEX_SHADER_ATTRIBUTE_NAME {
+                    struct_attrs.shadow_vertex_shader = Some(arg.value);
+                } else if arg.name == PREPASS_VERTEX_SHADER_ATTRIBUTE_NAME {
+                    struct_attrs.prepass_vertex_shader = Some(arg.value);
+                } else if arg.name == IMPOSTOR_VERTEX_SHADER_ATTRIBUTE_NAME {
+                    struct_attrs.impostor_vertex_shader = Some(arg.value);
+                } else if arg.name == INSTANCE_BOUNDS_WGSL_ATTRIBUTE_NAME {
+                    struct_attrs.instance_bounds_wgsl = Some(arg.value);
+                } else {
+                    return Err(Error::new_spanned(
+                        arg.name,
+                        "expected `shadow_vertex_shader = ...`, `prepass_vertex_shader = ...`, \
+                         `impostor_vertex_shader = ...` or `instance_bounds_wgsl = ...`",
+                    ));
+                }
+            }
+        }
+        Ok(struct_attrs)
+    }
+}
+
+/// `name = value`, where `value` may be any expression rather than only a literal.
+struct NameValueExpr {
+    name: Ident,
+    value: Expr,
+}
+
+impl Parse for NameValueExpr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+        Ok(NameValueExpr { name, value })
+    }
+}
+
+#[derive(Default)]
+struct FieldAttrs {
+    location: Option<u32>,
+    skip: bool,
+    position: bool,
+    animation_frame: bool,
+}
+
+impl FieldAttrs {
+    fn parse(field: &Field) -> Result<Self> {
+        let mut field_attrs = FieldAttrs::default();
+        for attr in &field.attrs {
+            if !attr.path.is_ident(INSTANCE_ATTRIBUTE_NAME) {
+                continue;
+            }
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                meta => {
+                    return Err(Error::new_spanned(
+                        meta,
+                        "expected #[instance(location = N)], #[instance(skip)], \
+                         #[instance(position)] or #[instance(animation_frame)]",
+                    ))
+                }
+            };
+            for nested in list.nested {
+                match nested {
+                    NestedMeta::Meta(Meta::NameValue(pair))
+                        if pair.path.is_ident(LOCATION_ATTRIBUTE_NAME) =>
+                    {
+                        match &pair.lit {
+                            Lit::Int(lit) => field_attrs.location = Some(lit.base10_parse()?),
+                            lit => {
+                                return Err(Error::new_spanned(
+                                    lit,
+                                    "expected an integer shader location",
+                                ))
+                            }
+                        }
+                    }
+                    NestedMeta::Meta(Meta::Path(path)) if path.is_ident(SKIP_ATTRIBUTE_NAME) => {
+                        field_attrs.skip = true;
+                    }
+                    NestedMeta::Meta(Meta::Path(path))
+                        if path.is_ident(POSITION_ATTRIBUTE_NAME) =>
+                    {
+                        field_attrs.position = true;
+                    }
+                    NestedMeta::Meta(Meta::Path(path))
+                        if path.is_ident(ANIMATION_FRAME_ATTRIBUTE_NAME) =>
+                    {
+                        field_attrs.animation_frame = true;
+                    }
+                    nested => {
+                        return Err(Error::new_spanned(
+                            nested,
+                            "expected `location = N`, `skip`, `position` or `animation_frame`",
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(field_attrs)
+    }
+}
+
+/// `InstanceData::position`, plus `InstanceData::radius` for a transform, read from `member`.
+fn bounds_fns(ty: &Type, member: TokenStream2) -> Result<TokenStream2> {
+    let math = quote! { ::bevy::math };
+    let ident = match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string()),
+        _ => None,
+    };
+    let (position, transform, transformed) = match ident.as_deref() {
+        Some("Vec3") => (
+            quote! { self.#member },
+            quote! { #math::Mat4::from_translation(self.#member) },
+            quote! { transform.transform_point3(self.#member) },
+        ),
+        Some("Vec4") => (
+            quote! { self.#member.truncate() },
+            quote! { #math::Mat4::from_translation(self.#member.truncate()) },
+            quote! { transform.transform_point3(self.#member.truncate()).extend(self.#member.w) },
+        ),
+        Some("Mat4") => (
+            quote! { self.#member.w_axis.truncate() },
+            quote! { self.#member },
+            quote! { transform * self.#member },
+        ),
+        _ => {
+            return Err(Error::new_spanned(
+                ty,
+                "#[instance(position)] expects a Vec3, Vec4 or Mat4 field",
+            ))
+        }
+    };
+    let radius = (ident.as_deref() == Some("Mat4")).then(|| {
+        quote! {
+            fn radius(&self, mesh_radius: f32) -> f32 {
+                let scale = self.#member.x_axis.truncate().length_squared()
+                    .max(self.#member.y_axis.truncate().length_squared())
+                    .max(self.#member.z_axis.truncate().length_squared());
+                mesh_radius * scale.sqrt()
+            }
+        }
+    });
+    Ok(quote! {
+        fn position(&self) -> ::std::option::Option<#math::Vec3> {
+            ::std::option::Option::Some(#position)
+        }
+
+        #radius
+
+        fn transform(&self) -> ::std::option::Option<#math::Mat4> {
+            ::std::option::Option::Some(#transform)
+        }
+
+        fn transformed(&self, transform: #math::Mat4) -> ::std::option::Option<Self> {
+            let mut transformed = *self;
+            transformed.#member = #transformed;
+            ::std::option::Option::Some(transformed)
+        }
+    })
+}
+
+/// The vertex formats a field type expands to, one per shader location.
+fn vertex_formats(ty: &Type) -> Result<Vec<TokenStream2>> {
+    let unsupported = || {
+        Error::new_spanned(
+            ty,
+            "unsupported InstanceData field type, expected Vec2, Vec3, Vec4, Mat4, u32, i32, f32 \
+             or [f32; N], or mark the field with #[instance(skip)]",
+        )
+    };
+    match ty {
+        Type::Path(path) => {
+            let ident = match path.path.segments.last() {
+                Some(segment) => segment.ident.to_string(),
+                None => return Err(unsupported()),
+            };
+            let formats = match ident.as_str() {
+                "f32" => vec![quote! { Float32 }],
+                "u32" => vec![quote! { Uint32 }],
+                "i32" => vec![quote! { Sint32 }],
+                "Vec2" => vec![quote! { Float32x2 }],
+                "Vec3" => vec![quote! { Float32x3 }],
+                "Vec4" => vec![quote! { Float32x4 }],
+                "Mat4" => vec![quote! { Float32x4 }; 4],
+                _ => return Err(unsupported()),
+            };
+            Ok(formats)
+        }
+        Type::Array(array) => {
+            match &*array.elem {
+                Type::Path(path) if path.path.is_ident("f32") => {}
+                _ => {
+                    return Err(Error::new_spanned(
+                        &array.elem,
+                        "only arrays of f32 are supported, as [f32; N]",
+                    ))
+                }
+            }
+            let len = match &array.len {
+                Expr::Lit(expr) => match &expr.lit {
+                    Lit::Int(lit) => lit.base10_parse::<usize>()?,
+                    lit => return Err(Error::new_spanned(lit, "expected an integer array length")),
+                },
+                len => {
+                    return Err(Error::new_spanned(
+                        len,
+                        "the length of an [f32; N] field must be an integer literal",
+                    ))
+                }
+            };
+            let format = match len {
+                1 => quote! { Float32 },
+                2 => quote! { Float32x2 },
+                3 => quote! { Float32x3 },
+                4 => quote! { Float32x4 },
+                _ => {
+                    return Err(Error::new_spanned(
+                        &array.len,
+                        format!(
+                            "[f32; {len}] doesn't fit a vertex attribute, N must be 1 to 4: split \
+                             it into several fields"
+                        ),
+                    ))
+                }
+            };
+            Ok(vec![format])
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn formats(ty: Type) -> Vec<String> {
+        vertex_formats(&ty)
+            .unwrap()
+            .into_iter()
+            .map(|format| format.to_string())
+            .collect()
+    }
+
+    fn error(ast: DeriveInput) -> String {
+        match instance_data_impl(ast) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn vertex_formats_of_supported_types() {
+        assert_eq!(formats(parse_quote!(f32)), ["Float32"]);
+        assert_eq!(formats(parse_quote!(u32)), ["Uint32"]);
+        assert_eq!(formats(parse_quote!(i32)), ["Sint32"]);
+        assert_eq!(formats(parse_quote!(Vec2)), ["Float32x2"]);
+        assert_eq!(formats(parse_quote!(bevy::math::Vec3)), ["Float32x3"]);
+        assert_eq!(formats(parse_quote!(Vec4)), ["Float32x4"]);
+        assert_eq!(formats(parse_quote!(Mat4)), ["Float32x4"; 4]);
+        assert_eq!(formats(parse_quote!([f32; 1])), ["Float32"]);
+        assert_eq!(formats(parse_quote!([f32; 3])), ["Float32x3"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_arrays() {
+        let message = vertex_formats(&parse_quote!([f32; 5]))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("N must be 1 to 4"), "{message}");
+        let message = vertex_formats(&parse_quote!([f32; 0]))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("N must be 1 to 4"), "{message}");
+        let message = vertex_formats(&parse_quote!([u32; 2]))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("only arrays of f32"), "{message}");
+        let message = vertex_formats(&parse_quote!([f32; LEN]))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("integer literal"), "{message}");
+    }
+
+    #[test]
+    fn rejects_overlapping_locations() {
+        let message = error(parse_quote! {
+            struct Instance {
+                #[instance(location = 8)]
+                transform: Mat4,
+                #[instance(location = 10)]
+                color: Vec4,
+            }
+        });
+        assert!(
+            message.contains("shader location 10 is already used"),
+            "{message}"
+        );
+
+        let message = error(parse_quote! {
+            struct Instance {
+                #[instance(location = 2)]
+                scale: f32,
+                #[instance(location = 1)]
+                offset: Vec2,
+                color: Vec4,
+            }
+        });
+        assert!(
+            message.contains("shader location 2 is already used"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn skipped_fields_take_no_location() {
+        assert!(instance_data_impl(parse_quote! {
+            struct Instance {
+                #[instance(location = 8)]
+                transform: Mat4,
+                #[instance(skip)]
+                padding: [u32; 4],
+                #[instance(location = 8, skip)]
+                more_padding: u32,
+                color: Vec4,
+            }
+        })
+        .is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+use bevy::{
+    prelude::*,
+    animation::{EntityPath, Keyframes, VariableCurve},
+    render::{
+        render_resource::{
+            BindGroupLayout, Extent3d, RenderPipelineDescriptor, TextureDimension, TextureFormat
+        },
+        mesh::{
+            skinning::{SkinnedMesh, SkinnedMeshInverseBindposes},
+            MeshVertexBufferLayout,
+        },
+    },
+    utils::HashMap,
+};
+
+use crate::InstanceData;
+
+pub const ANIMATION_TEXTURE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 9167250584119436011);
+
+/// Texels per joint, the first three rows of its matrix.
+const JOINT_TEXELS: usize = 3;
+
+/// Switches `descriptor` from bevy's skinning to the animation texture for skinned meshes, when
+/// `D` has an [`InstanceData::animation_frame`].
+///
+/// Only the material pipeline uses it, the bundled shadow and prepass shaders have no
+/// `ANIMATION_TEXTURE` path, see [`animated_by_texture`].
+///
+/// The joint attributes of the mesh are kept, the joint matrices bind group is replaced by the
+/// one of unskinned meshes, and shaders get the `ANIMATION_TEXTURE` def instead of `SKINNED`.
+pub fn use_animation_texture<D: InstanceData>(
+    descriptor: &mut RenderPipelineDescriptor,
+    mesh_layout: &BindGroupLayout,
+    skinned_mesh_layout: &BindGroupLayout,
+) {
+    if D::zeroed().animation_frame().is_none()
+        || !descriptor.vertex.shader_defs.iter().any(|shader_def| shader_def == "SKINNED")
+    {
+        return;
+    }
+
+    for layout in descriptor.layout.iter_mut().flatten() {
+        if *layout == *skinned_mesh_layout {
+            *layout = mesh_layout.clone();
+        }
+    }
+    let fragment_shader_defs = descriptor.fragment.as_mut().map(|fragment| &mut fragment.shader_defs);
+    for shader_defs in std::iter::once(&mut descriptor.vertex.shader_defs).chain(fragment_shader_defs) {
+        shader_defs.retain(|shader_def| shader_def != "SKINNED");
+        shader_defs.push("ANIMATION_TEXTURE".to_string());
+    }
+}
+
+/// Whether meshes with `layout` are skinned with the animation texture when instanced with `D`.
+///
+/// The shadow and prepass pipelines leave them out instead of drawing them in their bind pose:
+/// they cast no shadows and don't write to the prepasses.
+pub fn animated_by_texture<D: InstanceData>(layout: &MeshVertexBufferLayout) -> bool {
+    D::zeroed().animation_frame().is_some()
+        && layout.contains(Mesh::ATTRIBUTE_JOINT_INDEX)
+        && layout.contains(Mesh::ATTRIBUTE_JOINT_WEIGHT)
+}
+
+/// Samples the joint matrices of `skinned_mesh` playing `clip` at `frame_rate` into an
+/// `Rgba32Float` texture for [`InstanceData::animation_frame`].
+///
+/// Each row is a frame, from the start to the end of the clip, and each joint takes three
+/// texels. Joints are posed relative to `root`, the entity with the `AnimationPlayer` of the
+/// clip, so the mesh is expected to sit at the origin of `root`. `joints` has to reach every
+/// joint and its ancestors up to `root`.
+///
+/// Frames past the end of a curve hold its last keyframe, instead of leaving the joint where it
+/// was as `AnimationPlayer` does.
+///
+/// # Panics
+///
+/// If `frame_rate` isn't positive.
+pub fn bake_animation_texture(
+    clip: &AnimationClip,
+    skinned_mesh: &SkinnedMesh,
+    inverse_bindposes: &SkinnedMeshInverseBindposes,
+    root: Entity,
+    joints: &Query<(Option<&Name>, &Transform, Option<&Parent>)>,
+    frame_rate: f32,
+) -> Image {
+    assert!(frame_rate > 0.0, "the frame rate of an animation texture must be positive, got {frame_rate}");
+    let frame_count = (clip.duration() * frame_rate).ceil() as usize + 1;
+    let width = skinned_mesh.joints.len() * JOINT_TEXELS;
+
+    let mut texels: Vec<[f32; 4]> = Vec::with_capacity(width * frame_count);
+    for frame in 0..frame_count {
+        let time = frame as f32 / frame_rate;
+        let mut poses = HashMap::default();
+        for (joint, inverse_bindpose) in skinned_mesh.joints.iter().zip(inverse_bindposes.iter()) {
+            let joint_matrix = root_pose(clip, root, joints, *joint, time, &mut poses) * *inverse_bindpose;
+            texels.extend((0..JOINT_TEXELS).map(|row| joint_matrix.row(row).to_array()));
+        }
+    }
+
+    Image::new(
+        Extent3d {
+            width: width as u32,
+            height: frame_count as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        bytemuck::cast_slice(&texels).to_vec(),
+        TextureFormat::Rgba32Float,
+    )
+}
+
+/// Transform of `entity` relative to `root` at `time`, memoized in `poses` for the current frame.
+fn root_pose(
+    clip: &AnimationClip,
+    root: Entity,
+    joints: &Query<(Option<&Name>, &Transform, Option<&Parent>)>,
+    entity: Entity,
+    time: f32,
+    poses: &mut HashMap<Entity, Mat4>,
+) -> Mat4 {
+    if entity == root {
+        return Mat4::IDENTITY;
+    }
+    if let Some(pose) = poses.get(&entity) {
+        return *pose;
+    }
+
+    let (transform, parent) = match joints.get(entity) {
+        Ok((_, transform, parent)) => (transform, parent),
+        Err(_) => return Mat4::IDENTITY,
+    };
+    let mut local = *transform;
+    if let Some(curves) = entity_path(root, joints, entity).and_then(|path| clip.curves().get(&path)) {
+        for curve in curves {
+            sample_curve(curve, time, &mut local);
+        }
+    }
+    let parent_pose = match parent {
+        Some(parent) => root_pose(clip, root, joints, parent.get(), time, poses),
+        None => Mat4::IDENTITY,
+    };
+
+    let pose = parent_pose * local.compute_matrix();
+    poses.insert(entity, pose);
+    pose
+}
+
+/// Names from `root` down to `entity`, the way [`AnimationClip`] addresses its curves.
+fn entity_path(
+    root: Entity,
+    joints: &Query<(Option<&Name>, &Transform, Option<&Parent>)>,
+    entity: Entity,
+) -> Option<EntityPath> {
+    let mut parts = Vec::new();
+    let mut current = entity;
+    loop {
+        let (name, _, parent) = joints.get(current).ok()?;
+        parts.push(name?.clone());
+        if current == root {
+            break;
+        }
+        current = parent?.get();
+    }
+    parts.reverse();
+    Some(EntityPath { parts })
+}
+
+/// Sets the part of `transform` animated by `curve` to its value at `time`, clamped to the
+/// first and last keyframes.
+fn sample_curve(curve: &VariableCurve, time: f32, transform: &mut Transform) {
+    let timestamps = &curve.keyframe_timestamps;
+    if timestamps.is_empty() {
+        return;
+    }
+    let next = timestamps.partition_point(|timestamp| *timestamp <= time);
+    let (start, end, lerp) = if next == 0 {
+        (0, 0, 0.0)
+    } else if next == timestamps.len() {
+        (next - 1, next - 1, 0.0)
+    } else {
+        let start = next - 1;
+        (start, next, (time - timestamps[start]) / (timestamps[next] - timestamps[start]))
+    };
+
+    match &curve.keyframes {
+        Keyframes::Rotation(keyframes) => {
+            let rotation_start = keyframes[start].normalize();
+            let mut rotation_end = keyframes[end].normalize();
+            // Take the short path around.
+            if rotation_end.dot(rotation_start) < 0.0 {
+                rotation_end = -rotation_end;
+            }
+            transform.rotation = rotation_start.slerp(rotation_end, lerp);
+        }
+        Keyframes::Translation(keyframes) => {
+            transform.translation = keyframes[start].lerp(keyframes[end], lerp);
+        }
+        Keyframes::Scale(keyframes) => {
+            transform.scale = keyframes[start].lerp(keyframes[end], lerp);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+#define_import_path bevy_instancing::animation_texture
+
+// Joint matrices baked by `bake_animation_texture`: one row of the texture per frame, and three
+// texels per joint holding the first three rows of its matrix.
+fn animation_joint_matrix(animation: texture_2d<f32>, frame: u32, joint: u32) -> mat4x4<f32> {
+    let x = i32(joint * 3u);
+    let y = i32(frame);
+    let row_0 = textureLoad(animation, vec2<i32>(x, y), 0);
+    let row_1 = textureLoad(animation, vec2<i32>(x + 1, y), 0);
+    let row_2 = textureLoad(animation, vec2<i32>(x + 2, y), 0);
+    return transpose(mat4x4<f32>(row_0, row_1, row_2, vec4<f32>(0.0, 0.0, 0.0, 1.0)));
+}
+
+// Same as bevy's `skin_model`, with the joint matrices of `frame` instead of the ones of the entity.
+fn animation_skin_model(
+    animation: texture_2d<f32>,
+    frame: u32,
+    indexes: vec4<u32>,
+    weights: vec4<f32>
+) -> mat4x4<f32> {
+    return weights.x * animation_joint_matrix(animation, frame, indexes.x)
+        + weights.y * animation_joint_matrix(animation, frame, indexes.y)
+        + weights.z * animation_joint_matrix(animation, frame, indexes.z)
+        + weights.w * animation_joint_matrix(animation, frame, indexes.w);
+}
@@ -0,0 +1,407 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    asset::HandleId,
+    pbr::NotShadowCaster,
+    render::view::{NoFrustumCulling, VisibilitySystems},
+    transform::TransformSystem,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{calculate_instance_bounds, InstanceData, InstanceDataVec};
+
+/// Batches entities with a `Handle<Mesh>`, a `Handle<M>`, a [`GlobalTransform`] and an
+/// [`InstanceSource<D>`], so each entity can stay a regular entity while entities sharing a mesh
+/// and a material are drawn with one instanced draw.
+///
+/// Every mesh and material pair gets a batch entity holding an [`InstanceDataVec<D>`] with the
+/// instances of its sources, moved into world space by [`InstanceData::transformed`]. Batch
+/// entities are drawn like any other instanced entity, so [`InstanceMaterialPlugin<M, D>`] must
+/// be added too. Sources are only uploaded again when they change.
+///
+/// Sources hidden by their [`Visibility`] or the one of an ancestor are left out of their batch,
+/// as are sources despawned or missing one of the components above, whenever it happened.
+/// Batched sources get a [`NotShadowCaster`], removed again when they leave their batch, so
+/// bevy's shadow passes don't draw them on top of the shadows of their batch. Other components of
+/// the sources, e.g. a `NotShadowCaster` of their own, aren't carried over to the batch.
+///
+/// Without [`InstanceData::position`], batch entities get [`NoFrustumCulling`], as the bounds of
+/// their mesh at the origin don't enclose their sources.
+///
+/// # Panics
+///
+/// When added for a `D` which doesn't implement [`InstanceData::transformed`].
+///
+/// [`InstanceMaterialPlugin<M, D>`]: crate::InstanceMaterialPlugin
+pub struct InstanceBatchPlugin<M: Material, D: InstanceData> {
+    pub _marker: PhantomData<(M, D)>,
+}
+
+impl<M: Material, D: InstanceData> Default for InstanceBatchPlugin<M, D> {
+    fn default() -> Self {
+        InstanceBatchPlugin {
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<M: Material, D: InstanceData> Plugin for InstanceBatchPlugin<M, D> {
+    fn build(&self, app: &mut App) {
+        assert!(
+            D::zeroed().transformed(Mat4::IDENTITY).is_some(),
+            "{} must implement `InstanceData::transformed` to be batched, its instances would all be \
+             drawn at the origin of their batch",
+            D::buffer_label(),
+        );
+
+        app.init_resource::<InstanceBatches<M, D>>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                batch_instances::<M, D>
+                    .after(TransformSystem::TransformPropagate)
+                    .after(VisibilitySystems::VisibilityPropagate)
+                    .before(calculate_instance_bounds::<D>),
+            );
+    }
+}
+
+/// The instance of an entity batched by [`InstanceBatchPlugin`], in the local space of the entity.
+#[derive(Component, Clone, Copy)]
+pub struct InstanceSource<D: InstanceData>(pub D);
+
+/// Batch entity spawned by [`InstanceBatchPlugin`]: the source entity of each of its instances,
+/// in the order of its [`InstanceDataVec<D>`].
+#[derive(Component)]
+pub struct InstanceBatch<D: InstanceData> {
+    pub sources: Vec<Entity>,
+    _data: PhantomData<D>,
+}
+
+type InstanceBatchKey = (HandleId, HandleId);
+
+/// The batch entity of each mesh and material pair, and where each source is batched.
+#[derive(Resource)]
+pub struct InstanceBatches<M: Material, D: InstanceData> {
+    batches: HashMap<InstanceBatchKey, Entity>,
+    slots: HashMap<Entity, (InstanceBatchKey, usize)>,
+    /// Sources given a [`NotShadowCaster`] when batched.
+    not_shadow_casters: HashSet<Entity>,
+    _marker: PhantomData<(M, D)>,
+}
+
+impl<M: Material, D: InstanceData> Default for InstanceBatches<M, D> {
+    fn default() -> Self {
+        InstanceBatches {
+            batches: Default::default(),
+            slots: Default::default(),
+            not_shadow_casters: Default::default(),
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<M: Material, D: InstanceData> InstanceBatches<M, D> {
+    /// Batch entity of the `mesh` and `material` pair, if any source uses it.
+    pub fn batch(&self, mesh: &Handle<Mesh>, material: &Handle<M>) -> Option<Entity> {
+        self.batches.get(&(mesh.id(), material.id())).copied()
+    }
+
+    /// Batch entity of `source`, and the index of its instance.
+    pub fn slot(&self, source: Entity) -> Option<(Entity, usize)> {
+        let (key, index) = self.slots.get(&source)?;
+        Some((self.batches[key], *index))
+    }
+}
+
+/// Batches spawned during this run of [`batch_instances`], which aren't queryable yet.
+type SpawnedBatches<D> = HashMap<Entity, (InstanceDataVec<D>, InstanceBatch<D>)>;
+
+type SourceQuery<'w, 's, M, D, F = ()> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static InstanceSource<D>,
+        &'static Handle<Mesh>,
+        &'static Handle<M>,
+        &'static GlobalTransform,
+        Option<&'static ComputedVisibility>,
+        Option<&'static NotShadowCaster>,
+    ),
+    F,
+>;
+
+/// The instance of a source, moved into world space.
+fn world_instance<D: InstanceData>(instance_source: &InstanceSource<D>, transform: &GlobalTransform) -> D {
+    instance_source.0.transformed(transform.compute_matrix())
+        .expect("`InstanceBatchPlugin` only batches instances implementing `InstanceData::transformed`")
+}
+
+#[allow(clippy::type_complexity)]
+pub fn batch_instances<M: Material, D: InstanceData>(
+    mut commands: Commands,
+    mut instance_batches: ResMut<InstanceBatches<M, D>>,
+    changed_sources: SourceQuery<
+        M,
+        D,
+        Or<(
+            Changed<InstanceSource<D>>,
+            Changed<Handle<Mesh>>,
+            Changed<Handle<M>>,
+            Changed<GlobalTransform>,
+        )>,
+    >,
+    sources: SourceQuery<M, D>,
+    mut batches: Query<(&mut InstanceDataVec<D>, &mut InstanceBatch<D>)>,
+) {
+    let instance_batches = &mut *instance_batches;
+    let mut spawned = SpawnedBatches::default();
+
+    // Sources despawned or stripped of a component, which `RemovedComponents` would miss when it
+    // happens after this system, as bevy forgets removals at the end of the frame.
+    let removed = instance_batches.slots.keys()
+        .filter(|source| !sources.contains(**source))
+        .copied()
+        .collect::<Vec<_>>();
+    for source in removed {
+        remove_source(&mut commands, instance_batches, &mut batches, &mut spawned, source);
+    }
+
+    let visible = |computed_visibility: Option<&ComputedVisibility>| {
+        computed_visibility.map_or(true, ComputedVisibility::is_visible_in_hierarchy)
+    };
+    for (source, instance_source, mesh_handle, material_handle, transform, computed_visibility, not_shadow_caster)
+        in &changed_sources
+    {
+        if !visible(computed_visibility) {
+            remove_source(&mut commands, instance_batches, &mut batches, &mut spawned, source);
+            continue;
+        }
+        batch_source(
+            &mut commands, instance_batches, &mut batches, &mut spawned,
+            source, world_instance(instance_source, transform), mesh_handle, material_handle,
+            not_shadow_caster.is_some(),
+        );
+    }
+
+    // The visibility inherited from ancestors isn't change detected, `ComputedVisibility` is
+    // written every frame.
+    for (source, instance_source, mesh_handle, material_handle, transform, computed_visibility, not_shadow_caster)
+        in &sources
+    {
+        match (visible(computed_visibility), instance_batches.slots.contains_key(&source)) {
+            (false, true) => remove_source(&mut commands, instance_batches, &mut batches, &mut spawned, source),
+            (true, false) => batch_source(
+                &mut commands, instance_batches, &mut batches, &mut spawned,
+                source, world_instance(instance_source, transform), mesh_handle, material_handle,
+                not_shadow_caster.is_some(),
+            ),
+            _ => {}
+        }
+    }
+
+    for (batch, bundle) in spawned {
+        commands.entity(batch).insert(bundle);
+    }
+}
+
+/// Updates the instance of `source` in its batch, or moves it to the batch of `mesh_handle` and
+/// `material_handle`, spawning it if needed. A newly batched source gets a [`NotShadowCaster`]
+/// unless it has one already.
+#[allow(clippy::too_many_arguments)]
+fn batch_source<M: Material, D: InstanceData>(
+    commands: &mut Commands,
+    instance_batches: &mut InstanceBatches<M, D>,
+    batches: &mut Query<(&mut InstanceDataVec<D>, &mut InstanceBatch<D>)>,
+    spawned: &mut SpawnedBatches<D>,
+    source: Entity,
+    instance: D,
+    mesh_handle: &Handle<Mesh>,
+    material_handle: &Handle<M>,
+    not_shadow_caster: bool,
+) {
+    let key = (mesh_handle.id(), material_handle.id());
+    let marked = instance_batches.not_shadow_casters.contains(&source);
+    match instance_batches.slots.get(&source) {
+        Some((slot_key, index)) if *slot_key == key => {
+            let batch = instance_batches.batches[&key];
+            match batches.get_mut(batch) {
+                Ok((mut instance_data, _)) => instance_data.set(*index, instance),
+                Err(_) => spawned.get_mut(&batch).unwrap().0.set(*index, instance),
+            }
+            return;
+        }
+        Some(_) => remove_source(commands, instance_batches, batches, spawned, source),
+        None => {}
+    }
+
+    // A source moving between batches keeps the marker it was given.
+    if marked || !not_shadow_caster {
+        commands.entity(source).insert(NotShadowCaster);
+        instance_batches.not_shadow_casters.insert(source);
+    }
+
+    let batch = *instance_batches.batches.entry(key).or_insert_with(|| {
+        let mut batch = commands.spawn((
+            mesh_handle.clone(),
+            material_handle.clone(),
+            SpatialBundle::default(),
+        ));
+        if D::zeroed().position().is_none() {
+            batch.insert(NoFrustumCulling);
+        }
+        let batch = batch.id();
+        let instance_batch = InstanceBatch { sources: Vec::new(), _data: PhantomData };
+        spawned.insert(batch, (InstanceDataVec::new(), instance_batch));
+        batch
+    });
+    let index = match batches.get_mut(batch) {
+        Ok((mut instance_data, mut instance_batch)) => {
+            push_source(&mut instance_data, &mut instance_batch, source, instance)
+        }
+        Err(_) => {
+            let (instance_data, instance_batch) = spawned.get_mut(&batch).unwrap();
+            push_source(instance_data, instance_batch, source, instance)
+        }
+    };
+    instance_batches.slots.insert(source, (key, index));
+}
+
+fn push_source<D: InstanceData>(
+    instance_data: &mut InstanceDataVec<D>,
+    instance_batch: &mut InstanceBatch<D>,
+    source: Entity,
+    instance: D,
+) -> usize {
+    instance_data.push(instance);
+    instance_batch.sources.push(source);
+    instance_batch.sources.len() - 1
+}
+
+/// Returns the source whose instance took the place of the removed one, and whether the batch is
+/// now empty.
+fn swap_remove_source<D: InstanceData>(
+    instance_data: &mut InstanceDataVec<D>,
+    instance_batch: &mut InstanceBatch<D>,
+    index: usize,
+) -> (Option<Entity>, bool) {
+    instance_data.swap_remove(index);
+    instance_batch.sources.swap_remove(index);
+    (instance_batch.sources.get(index).copied(), instance_batch.sources.is_empty())
+}
+
+/// Swap removes the instance of `source` from its batch, and despawns the batch once empty. The
+/// [`NotShadowCaster`] given to `source` when batched is removed, if it still exists.
+fn remove_source<M: Material, D: InstanceData>(
+    commands: &mut Commands,
+    instance_batches: &mut InstanceBatches<M, D>,
+    batches: &mut Query<(&mut InstanceDataVec<D>, &mut InstanceBatch<D>)>,
+    spawned: &mut SpawnedBatches<D>,
+    source: Entity,
+) {
+    let (key, index) = match instance_batches.slots.remove(&source) {
+        Some(slot) => slot,
+        None => return,
+    };
+    if instance_batches.not_shadow_casters.remove(&source) {
+        if let Some(mut source) = commands.get_entity(source) {
+            source.remove::<NotShadowCaster>();
+        }
+    }
+    let batch = instance_batches.batches[&key];
+    let (moved, empty) = match batches.get_mut(batch) {
+        Ok((mut instance_data, mut instance_batch)) => {
+            swap_remove_source(&mut instance_data, &mut instance_batch, index)
+        }
+        Err(_) => {
+            let (instance_data, instance_batch) = spawned.get_mut(&batch).unwrap();
+            swap_remove_source(instance_data, instance_batch, index)
+        }
+    };
+
+    if let Some(moved) = moved {
+        instance_batches.slots.insert(moved, (key, index));
+    }
+    if empty {
+        instance_batches.batches.remove(&key);
+        spawned.remove(&batch);
+        commands.entity(batch).despawn();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InstancedStandardMaterial, StandardInstanceData};
+
+    type Batches = InstanceBatches<InstancedStandardMaterial, StandardInstanceData>;
+
+    /// Marks a source to lose its [`InstanceSource`] in [`CoreStage::Last`].
+    #[derive(Component)]
+    struct RemoveLate;
+
+    fn remove_late(mut commands: Commands, sources: Query<Entity, With<RemoveLate>>) {
+        for source in &sources {
+            commands.entity(source).remove::<InstanceSource<StandardInstanceData>>();
+        }
+    }
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugin(InstanceBatchPlugin::<InstancedStandardMaterial, StandardInstanceData>::default())
+            .add_system_to_stage(CoreStage::Last, remove_late);
+        app
+    }
+
+    fn spawn_source(app: &mut App, translation: Vec3) -> Entity {
+        app.world.spawn((
+            InstanceSource(StandardInstanceData::default()),
+            Handle::<Mesh>::default(),
+            Handle::<InstancedStandardMaterial>::default(),
+            GlobalTransform::from_translation(translation),
+        )).id()
+    }
+
+    /// Entities bevy's shadow queue draws, as it skips meshes with a [`NotShadowCaster`].
+    fn shadow_casters(app: &mut App) -> Vec<Entity> {
+        let mut casters = app.world.query_filtered::<Entity, (With<Handle<Mesh>>, Without<NotShadowCaster>)>()
+            .iter(&app.world)
+            .collect::<Vec<_>>();
+        casters.sort_unstable();
+        casters
+    }
+
+    #[test]
+    fn batched_sources_are_left_to_the_shadows_of_their_batch() {
+        let mut app = app();
+        let source = spawn_source(&mut app, Vec3::X);
+        app.update();
+
+        let (batch, index) = app.world.resource::<Batches>().slot(source).unwrap();
+        assert_eq!(shadow_casters(&mut app), vec![batch]);
+        let instances = app.world.get::<InstanceDataVec<StandardInstanceData>>(batch).unwrap();
+        assert_eq!(instances[index].transform, Mat4::from_translation(Vec3::X));
+
+        app.world.entity_mut(source).remove::<InstanceSource<StandardInstanceData>>();
+        app.update();
+        assert_eq!(app.world.resource::<Batches>().slot(source), None);
+        assert_eq!(shadow_casters(&mut app), vec![source]);
+    }
+
+    #[test]
+    fn sources_removed_late_in_the_frame_leave_their_batch() {
+        let mut app = app();
+        let kept = spawn_source(&mut app, Vec3::X);
+        let removed = spawn_source(&mut app, Vec3::Y);
+        app.update();
+
+        app.world.entity_mut(removed).insert(RemoveLate);
+        app.update();
+        app.update();
+        let batches = app.world.resource::<Batches>();
+        assert_eq!(batches.slot(removed), None);
+        let (batch, _) = batches.slot(kept).unwrap();
+        assert_eq!(app.world.get::<InstanceBatch<StandardInstanceData>>(batch).unwrap().sources, vec![kept]);
+    }
+}
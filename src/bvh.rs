@@ -0,0 +1,697 @@
+use std::ops::Range;
+
+use bevy::{prelude::*, render::primitives::Frustum, utils::HashSet};
+
+use crate::{mesh_radius, InstanceData, InstanceDataVec};
+
+/// Instances per leaf of an [`InstanceBvh`].
+const LEAF_SIZE: usize = 4;
+
+/// Axis aligned box, as min and max corners.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BvhBounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl BvhBounds {
+    pub const EMPTY: BvhBounds = BvhBounds {
+        min: Vec3::splat(f32::MAX),
+        max: Vec3::splat(f32::MIN),
+    };
+
+    /// Box around a sphere.
+    pub fn from_sphere(center: Vec3, radius: f32) -> Self {
+        BvhBounds {
+            min: center - radius,
+            max: center + radius,
+        }
+    }
+
+    pub fn union(self, other: BvhBounds) -> Self {
+        BvhBounds {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn overlaps(&self, other: &BvhBounds) -> bool {
+        self.min.cmple(other.max).all() && self.max.cmpge(other.min).all()
+    }
+
+    /// Squared distance from `point` to the closest point of the box, zero inside it.
+    pub fn distance_squared(&self, point: Vec3) -> f32 {
+        point.clamp(self.min, self.max).distance_squared(point)
+    }
+
+    /// Half the surface area, zero for [`BvhBounds::EMPTY`].
+    fn half_area(&self) -> f32 {
+        let extents = (self.max - self.min).max(Vec3::ZERO);
+        extents.x * extents.y + extents.y * extents.z + extents.z * extents.x
+    }
+
+    /// Signed distance to `plane`, as normal and distance, of the corner farthest along its
+    /// normal. The box is entirely behind the plane when it isn't positive.
+    fn max_plane_distance(&self, plane: Vec4) -> f32 {
+        let normal = plane.truncate();
+        normal.dot(self.center()) + plane.w + normal.abs().dot((self.max - self.min) * 0.5)
+    }
+
+    /// Distance along the ray where it enters the box, `None` if it misses it. `direction`
+    /// doesn't need to be normalized, the distance is in multiples of it.
+    pub fn ray_distance(&self, origin: Vec3, direction: Vec3) -> Option<f32> {
+        let inverse_direction = direction.recip();
+        let t0 = (self.min - origin) * inverse_direction;
+        let t1 = (self.max - origin) * inverse_direction;
+        let near = t0.min(t1).max_element().max(0.0);
+        let far = t0.max(t1).min_element();
+        (near <= far).then_some(near)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BvhNode {
+    bounds: BvhBounds,
+    /// Children at `first` and `first + 1` for inner nodes, `count` instances from `first` in
+    /// `indices` for leaves, which have room for [`LEAF_SIZE`] there.
+    first: usize,
+    count: usize,
+}
+
+/// Bounding volume hierarchy over the bounds of instances, in the local space of their entity.
+///
+/// Inserted next to an [`InstanceDataVec`](crate::InstanceDataVec), it is kept in sync with the
+/// instances by [`update_instance_bvhs`]. [`raycast_instances`](crate::raycast_instances) reuses
+/// it, and gameplay code can look up instances near a point with [`InstanceBvh::query_sphere`].
+/// It also opts the entity into hierarchical frustum culling: the render world keeps its own
+/// hierarchy over the extracted instances, so culling and the
+/// [`InstanceLod`](crate::InstanceLod) levels only go through the instances found in view.
+/// Entities without one scan every instance instead.
+///
+/// Needs [`InstanceData::position`].
+#[derive(Component, Clone, Debug, Default)]
+pub struct InstanceBvh {
+    nodes: Vec<BvhNode>,
+    /// Parent of each node, the root is its own parent.
+    parents: Vec<usize>,
+    /// Instance indices, in a slot of [`LEAF_SIZE`] per leaf.
+    indices: Vec<usize>,
+    /// Leaf holding each instance.
+    leaves: Vec<usize>,
+    bounds: Vec<BvhBounds>,
+    /// Mesh radius the bounds of the instances were computed with.
+    mesh_radius: Option<f32>,
+    /// Instances moved, added or removed since the hierarchy was built.
+    refits: usize,
+    /// [`InstanceDataVec`](crate::InstanceDataVec) changes the hierarchy was last synced with.
+    changes: Option<u64>,
+}
+
+fn instance_bounds<D: InstanceData>(instance: &D, mesh_radius: f32) -> BvhBounds {
+    BvhBounds::from_sphere(instance.position().unwrap_or_default(), instance.radius(mesh_radius))
+}
+
+/// Median split of `indices` along the longest axis of the centers of their `bounds`, returning
+/// where the second half starts.
+fn partition(indices: &mut [usize], bounds: &[BvhBounds]) -> usize {
+    let centers = indices.iter()
+        .fold(BvhBounds::EMPTY, |centers, index| {
+            let center = bounds[*index].center();
+            centers.union(BvhBounds { min: center, max: center })
+        });
+    let extents = centers.max - centers.min;
+    let axis = if extents.x >= extents.y && extents.x >= extents.z {
+        0
+    } else if extents.y >= extents.z {
+        1
+    } else {
+        2
+    };
+    let half = indices.len() / 2;
+    indices.select_nth_unstable_by(half, |a, b| {
+        bounds[*a].center()[axis].total_cmp(&bounds[*b].center()[axis])
+    });
+    half
+}
+
+impl InstanceBvh {
+    /// Builds the hierarchy over the spheres of `instances`, `None` without
+    /// [`InstanceData::position`].
+    pub fn from_instances<D: InstanceData>(instances: &[D], mesh_radius: f32) -> Option<Self> {
+        D::zeroed().position()?;
+        let mut bvh = Self::new(instances.iter()
+            .map(|instance| instance_bounds(instance, mesh_radius))
+            .collect());
+        bvh.mesh_radius = Some(mesh_radius);
+        Some(bvh)
+    }
+
+    /// Builds the hierarchy over the bounds of each instance.
+    pub fn new(bounds: Vec<BvhBounds>) -> Self {
+        let mut bvh = InstanceBvh {
+            nodes: Vec::with_capacity((2 * bounds.len() / LEAF_SIZE).max(1)),
+            parents: Vec::with_capacity((2 * bounds.len() / LEAF_SIZE).max(1)),
+            indices: (0..bounds.len()).collect(),
+            leaves: vec![0; bounds.len()],
+            bounds,
+            mesh_radius: None,
+            refits: 0,
+            changes: None,
+        };
+        bvh.nodes.push(BvhNode {
+            bounds: BvhBounds::EMPTY,
+            first: 0,
+            count: bvh.indices.len(),
+        });
+        bvh.parents.push(0);
+        bvh.split(0);
+
+        // Leaves get room to grow in place, see `InstanceBvh::push`.
+        let mut slots = Vec::with_capacity(bvh.indices.len() * 2);
+        for node in bvh.nodes.iter_mut().filter(|node| node.count > 0) {
+            let slot = slots.len();
+            slots.extend_from_slice(&bvh.indices[node.first..node.first + node.count]);
+            slots.resize(slot + LEAF_SIZE, 0);
+            node.first = slot;
+        }
+        bvh.indices = slots;
+        bvh
+    }
+
+    /// Brings the hierarchy in line with `instances` after the instances in `ranges` changed,
+    /// e.g. the [`InstanceDataVec::dirty_ranges`](crate::InstanceDataVec::dirty_ranges).
+    ///
+    /// Instances appended since are inserted with [`InstanceBvh::push`] and instances past the
+    /// end of `instances` dropped with [`InstanceBvh::pop`], which covers
+    /// [`InstanceDataVec::swap_remove`](crate::InstanceDataVec::swap_remove) as the slot the last
+    /// instance moved to is dirty. The nodes above the changed instances are refitted. The
+    /// hierarchy is built again when `mesh_radius` changed, or once it moved, added or removed as
+    /// many instances as it holds, as the nodes then overlap more and more.
+    pub fn update_instances<D: InstanceData>(
+        &mut self,
+        instances: &[D],
+        ranges: &[Range<usize>],
+        mesh_radius: f32,
+    ) {
+        let kept = instances.len().min(self.len());
+        let moved = ranges.iter()
+            .map(|range| range.start.min(kept)..range.end.min(kept))
+            .map(|range| range.len())
+            .sum::<usize>()
+            + instances.len().abs_diff(self.len());
+        if self.mesh_radius != Some(mesh_radius)
+            || self.is_empty()
+            || instances.is_empty()
+            || self.refits + moved > instances.len()
+        {
+            *self = Self::new(instances.iter()
+                .map(|instance| instance_bounds(instance, mesh_radius))
+                .collect());
+            self.mesh_radius = Some(mesh_radius);
+            return;
+        }
+        while self.len() > instances.len() {
+            self.pop();
+        }
+        while self.len() < instances.len() {
+            self.push(instance_bounds(&instances[self.len()], mesh_radius));
+        }
+        for range in ranges {
+            for index in range.start..range.end.min(kept) {
+                self.update(index, instance_bounds(&instances[index], mesh_radius));
+            }
+        }
+    }
+
+    /// Brings the hierarchy in line with `instance_data`, from the instances changed since it was
+    /// last synced with it.
+    pub(crate) fn sync<D: InstanceData>(&mut self, instance_data: &InstanceDataVec<D>, mesh_radius: f32) {
+        let ranges = match self.changes {
+            Some(changes) => match instance_data.changed_ranges_since(changes) {
+                Some(ranges) => ranges,
+                None if self.mesh_radius == Some(mesh_radius) => return,
+                None => Vec::new(),
+            },
+            None => vec![0..instance_data.len()],
+        };
+        self.update_instances(instance_data, &ranges, mesh_radius);
+        self.changes = Some(instance_data.changes());
+    }
+
+    /// Whether the hierarchy was synced with the current instances of `instance_data`.
+    pub(crate) fn is_synced<D: InstanceData>(&self, instance_data: &InstanceDataVec<D>) -> bool {
+        self.changes == Some(instance_data.changes())
+    }
+
+    /// Moves the instance at `index` to `bounds`, refitting the nodes above it.
+    pub fn update(&mut self, index: usize, bounds: BvhBounds) {
+        self.bounds[index] = bounds;
+        self.refits += 1;
+        self.refit(self.leaves[index]);
+    }
+
+    /// Adds an instance with `bounds` after the others, to the leaf whose bounds grow the least.
+    /// A full leaf is split in two.
+    pub fn push(&mut self, bounds: BvhBounds) {
+        if self.is_empty() {
+            let mesh_radius = self.mesh_radius;
+            *self = Self::new(vec![bounds]);
+            self.mesh_radius = mesh_radius;
+            return;
+        }
+        let index = self.bounds.len();
+        self.bounds.push(bounds);
+        self.leaves.push(0);
+        self.refits += 1;
+
+        let growth = |node: &BvhNode| node.bounds.union(bounds).half_area() - node.bounds.half_area();
+        let mut node = 0;
+        while self.nodes[node].count == 0 {
+            let first = self.nodes[node].first;
+            node = if growth(&self.nodes[first]) <= growth(&self.nodes[first + 1]) {
+                first
+            } else {
+                first + 1
+            };
+        }
+
+        let BvhNode { first, count, .. } = self.nodes[node];
+        if count < LEAF_SIZE {
+            self.indices[first + count] = index;
+            self.nodes[node].count += 1;
+            self.leaves[index] = node;
+            self.refit(node);
+            return;
+        }
+
+        // The leaf becomes the parent of two leaves with slots of their own, its slot is left
+        // unused until the hierarchy is built again.
+        let mut indices = self.indices[first..first + count].to_vec();
+        indices.push(index);
+        let half = partition(&mut indices, &self.bounds);
+        let children = self.nodes.len();
+        for (child, indices) in [&indices[..half], &indices[half..]].into_iter().enumerate() {
+            let slot = self.indices.len();
+            self.indices.extend_from_slice(indices);
+            self.indices.resize(slot + LEAF_SIZE, 0);
+            for index in indices {
+                self.leaves[*index] = children + child;
+            }
+            self.nodes.push(BvhNode {
+                bounds: indices.iter()
+                    .fold(BvhBounds::EMPTY, |node_bounds, index| node_bounds.union(self.bounds[*index])),
+                first: slot,
+                count: indices.len(),
+            });
+            self.parents.push(node);
+        }
+        self.nodes[node].first = children;
+        self.nodes[node].count = 0;
+        self.refit(node);
+    }
+
+    /// Removes the last instance. Its leaf is replaced by its sibling once empty.
+    pub fn pop(&mut self) {
+        let index = match self.bounds.len().checked_sub(1) {
+            Some(index) => index,
+            None => return,
+        };
+        let node = self.leaves[index];
+        self.bounds.pop();
+        self.leaves.pop();
+        self.refits += 1;
+
+        let BvhNode { first, count, .. } = self.nodes[node];
+        let slot = &mut self.indices[first..first + count];
+        let position = slot.iter().position(|slot_index| *slot_index == index)
+            .expect("instance missing from its leaf");
+        slot.swap(position, count - 1);
+        self.nodes[node].count -= 1;
+        if count > 1 || node == 0 {
+            self.refit(node);
+            return;
+        }
+
+        // Leaves are never empty, only the root once every instance is removed.
+        let parent = self.parents[node];
+        let sibling = self.nodes[self.nodes[parent].first + usize::from(node == self.nodes[parent].first)];
+        self.nodes[parent] = sibling;
+        if sibling.count == 0 {
+            self.parents[sibling.first] = parent;
+            self.parents[sibling.first + 1] = parent;
+        } else {
+            for index in &self.indices[sibling.first..sibling.first + sibling.count] {
+                self.leaves[*index] = parent;
+            }
+        }
+        // The parent has the bounds of the sibling, which no longer include the instance.
+        if parent != 0 {
+            self.refit(self.parents[parent]);
+        }
+    }
+
+    /// Recomputes the bounds of `node` and the nodes above it, as far as they change.
+    fn refit(&mut self, mut node: usize) {
+        loop {
+            let BvhNode { first, count, .. } = self.nodes[node];
+            let node_bounds = if count == 0 {
+                self.nodes[first].bounds.union(self.nodes[first + 1].bounds)
+            } else {
+                self.indices[first..first + count].iter()
+                    .fold(BvhBounds::EMPTY, |node_bounds, index| node_bounds.union(self.bounds[*index]))
+            };
+            // The nodes above only depend on this one.
+            if node_bounds == self.nodes[node].bounds {
+                break;
+            }
+            self.nodes[node].bounds = node_bounds;
+            if node == 0 {
+                break;
+            }
+            node = self.parents[node];
+        }
+    }
+
+    fn split(&mut self, node: usize) {
+        let BvhNode { first, count, .. } = self.nodes[node];
+        let indices = &mut self.indices[first..first + count];
+        let bounds = &self.bounds;
+        self.nodes[node].bounds = indices.iter()
+            .fold(BvhBounds::EMPTY, |node_bounds, index| node_bounds.union(bounds[*index]));
+        if count <= LEAF_SIZE {
+            for index in indices.iter() {
+                self.leaves[*index] = node;
+            }
+            return;
+        }
+
+        let half = partition(indices, bounds);
+        let children = self.nodes.len();
+        self.nodes.push(BvhNode { bounds: BvhBounds::EMPTY, first, count: half });
+        self.nodes.push(BvhNode { bounds: BvhBounds::EMPTY, first: first + half, count: count - half });
+        self.parents.extend([node, node]);
+        self.nodes[node].first = children;
+        self.nodes[node].count = 0;
+        self.split(children);
+        self.split(children + 1);
+    }
+
+    /// Bounds of the instance at `index`.
+    pub fn bounds(&self, index: usize) -> Option<BvhBounds> {
+        self.bounds.get(index).copied()
+    }
+
+    /// Bounds of every instance.
+    pub fn root_bounds(&self) -> Option<BvhBounds> {
+        self.nodes.first()
+            .filter(|_| !self.is_empty())
+            .map(|root| root.bounds)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    /// Calls `f` with the index of each instance whose bounds pass `overlaps`, which is also
+    /// given the bounds of the nodes to descend into.
+    pub fn query(&self, mut overlaps: impl FnMut(&BvhBounds) -> bool, mut f: impl FnMut(usize)) {
+        if self.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let BvhNode { bounds, first, count } = self.nodes[node];
+            if !overlaps(&bounds) {
+                continue;
+            }
+            if count == 0 {
+                stack.push(first);
+                stack.push(first + 1);
+                continue;
+            }
+            for index in &self.indices[first..first + count] {
+                if overlaps(&self.bounds[*index]) {
+                    f(*index);
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with the index of each instance whose bounds overlap `aabb`.
+    pub fn query_aabb(&self, aabb: BvhBounds, f: impl FnMut(usize)) {
+        self.query(|bounds| bounds.overlaps(&aabb), f);
+    }
+
+    /// Calls `f` with the index of each instance whose bounds overlap the sphere.
+    pub fn query_sphere(&self, center: Vec3, radius: f32, f: impl FnMut(usize)) {
+        self.query(|bounds| bounds.distance_squared(center) <= radius * radius, f);
+    }
+
+    /// Calls `f` with the index of each instance whose bounds are at least partly inside
+    /// `frustum`, once placed by `local_to_world`, e.g. the transform of the entity. As with
+    /// [`Frustum::intersects_sphere`], the far plane is only tested with `intersect_far`.
+    pub fn query_frustum(
+        &self,
+        frustum: &Frustum,
+        local_to_world: &Mat4,
+        intersect_far: bool,
+        f: impl FnMut(usize),
+    ) {
+        // Planes move to local space by the transpose of the transform.
+        let world_to_local = local_to_world.transpose();
+        let planes = &frustum.planes[..if intersect_far { 6 } else { 5 }];
+        let local_planes = planes.iter()
+            .map(|plane| world_to_local * plane.normal_d())
+            .collect::<Vec<_>>();
+        self.query(
+            |bounds| local_planes.iter().all(|plane| bounds.max_plane_distance(*plane) > 0.0),
+            f,
+        );
+    }
+
+    /// Calls `f` with the index of each instance whose bounds the ray hits, and the distance
+    /// where it enters them, in multiples of `direction`.
+    pub fn query_ray(&self, origin: Vec3, direction: Vec3, mut f: impl FnMut(usize, f32)) {
+        self.query(
+            |bounds| bounds.ray_distance(origin, direction).is_some(),
+            |index| {
+                if let Some(distance) = self.bounds[index].ray_distance(origin, direction) {
+                    f(index, distance);
+                }
+            },
+        );
+    }
+}
+
+/// Keeps each [`InstanceBvh`] in sync with the [`InstanceDataVec`] next to it, from the instances
+/// changed since it was last synced, including instances marked through
+/// [`Mut::bypass_change_detection`].
+///
+/// Runs in [`CoreStage::PostUpdate`], instances changed later in the frame are caught up with
+/// the next frame.
+pub fn update_instance_bvhs<D: InstanceData>(
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+    mut query: Query<(
+        &Handle<Mesh>,
+        ChangeTrackers<Handle<Mesh>>,
+        &InstanceDataVec<D>,
+        &mut InstanceBvh,
+    )>,
+) {
+    if D::zeroed().position().is_none() {
+        return;
+    }
+
+    let changed_meshes = mesh_events.iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (mesh_handle, mesh_tracker, instance_data, mut bvh) in &mut query {
+        let mesh_changed = mesh_tracker.is_changed() || changed_meshes.contains(mesh_handle);
+        if !mesh_changed && bvh.is_synced(instance_data) {
+            continue;
+        }
+        if let Some(mesh_radius) = meshes.get(mesh_handle).and_then(mesh_radius) {
+            bvh.sync(instance_data, mesh_radius);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::primitives::Plane;
+
+    use super::*;
+    use crate::StandardInstanceData;
+
+    fn instance(position: Vec3) -> StandardInstanceData {
+        StandardInstanceData {
+            transform: Mat4::from_translation(position),
+            color: Vec4::ONE,
+        }
+    }
+
+    /// Instances scattered over a box of 100 units, the same ones for a given `seed`.
+    fn random_instances(len: usize, mut seed: u32) -> Vec<StandardInstanceData> {
+        let mut random = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 8) as f32 / (1 << 24) as f32 * 100.0
+        };
+        (0..len)
+            .map(|_| instance(Vec3::new(random(), random(), random())))
+            .collect()
+    }
+
+    fn sorted(mut indices: Vec<usize>) -> Vec<usize> {
+        indices.sort_unstable();
+        indices
+    }
+
+    /// Checks every query of `bvh` against a scan over the bounds of `instances`.
+    fn assert_queries_match_scan(bvh: &InstanceBvh, instances: &[StandardInstanceData]) {
+        let bounds = instances.iter()
+            .map(|instance| instance_bounds(instance, 1.0))
+            .collect::<Vec<_>>();
+        let scan = |filter: &dyn Fn(&BvhBounds) -> bool| {
+            (0..bounds.len()).filter(|index| filter(&bounds[*index])).collect::<Vec<_>>()
+        };
+        assert_eq!(bvh.len(), instances.len());
+        assert_eq!(
+            bvh.root_bounds(),
+            Some(bounds.iter().fold(BvhBounds::EMPTY, |root, bounds| root.union(*bounds))),
+        );
+
+        for step in 0..20 {
+            let center = Vec3::new(step as f32 * 5.0, 50.0, 40.0);
+            let mut found = Vec::new();
+            bvh.query_sphere(center, 10.0, |index| found.push(index));
+            assert_eq!(sorted(found), scan(&|bounds| bounds.distance_squared(center) <= 100.0));
+
+            let aabb = BvhBounds { min: center - 7.0, max: center + 3.0 };
+            let mut found = Vec::new();
+            bvh.query_aabb(aabb, |index| found.push(index));
+            assert_eq!(sorted(found), scan(&|bounds| bounds.overlaps(&aabb)));
+
+            let origin = Vec3::new(-50.0, step as f32 * 5.0, 40.0);
+            let direction = Vec3::new(1.0, 0.2, 0.1);
+            let mut found = Vec::new();
+            bvh.query_ray(origin, direction, |index, distance| found.push((index, distance)));
+            found.sort_unstable_by_key(|(index, _)| *index);
+            let expected = (0..bounds.len())
+                .filter_map(|index| Some((index, bounds[index].ray_distance(origin, direction)?)))
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected);
+        }
+
+        // The box 20 < x < 80, 10 < y < 90, 0 < z < 60, in a space scaled by 2 and moved by 10
+        // along x.
+        let frustum = Frustum {
+            planes: [
+                Plane::new(Vec4::new(1.0, 0.0, 0.0, -20.0)),
+                Plane::new(Vec4::new(-1.0, 0.0, 0.0, 80.0)),
+                Plane::new(Vec4::new(0.0, 1.0, 0.0, -10.0)),
+                Plane::new(Vec4::new(0.0, -1.0, 0.0, 90.0)),
+                Plane::new(Vec4::new(0.0, 0.0, 1.0, 0.0)),
+                Plane::new(Vec4::new(0.0, 0.0, -1.0, 60.0)),
+            ],
+        };
+        let local_to_world = Mat4::from_translation(Vec3::X * 10.0) * Mat4::from_scale(Vec3::splat(2.0));
+        for intersect_far in [false, true] {
+            let mut found = Vec::new();
+            bvh.query_frustum(&frustum, &local_to_world, intersect_far, |index| found.push(index));
+            let expected = scan(&|bounds| {
+                let min = local_to_world.transform_point3(bounds.min);
+                let max = local_to_world.transform_point3(bounds.max);
+                max.x > 20.0 && min.x < 80.0 && max.y > 10.0 && min.y < 90.0 && max.z > 0.0
+                    && (!intersect_far || min.z < 60.0)
+            });
+            assert!(!expected.is_empty());
+            assert_eq!(sorted(found), expected);
+        }
+    }
+
+    #[test]
+    fn queries_match_a_scan() {
+        let instances = random_instances(1000, 1);
+        let bvh = InstanceBvh::from_instances(&instances, 1.0).unwrap();
+        assert_queries_match_scan(&bvh, &instances);
+
+        let mut empty = InstanceBvh::default();
+        empty.query_sphere(Vec3::ZERO, f32::MAX, |_| panic!("empty hierarchies find nothing"));
+        empty.update_instances(&instances, &[], 1.0);
+        assert_queries_match_scan(&empty, &instances);
+    }
+
+    #[test]
+    fn moved_instances_are_refitted() {
+        let mut instances = random_instances(1000, 2);
+        let mut bvh = InstanceBvh::from_instances(&instances, 1.0).unwrap();
+        for index in (0..instances.len()).step_by(7) {
+            instances[index] = instance(instances[index].transform.w_axis.truncate() + Vec3::new(30.0, -20.0, 5.0));
+        }
+        let ranges = (0..instances.len()).step_by(7).map(|index| index..index + 1).collect::<Vec<_>>();
+        bvh.update_instances(&instances, &ranges, 1.0);
+        assert_eq!(bvh.refits, ranges.len());
+        assert_queries_match_scan(&bvh, &instances);
+
+        // Built again once as many instances moved as it holds.
+        bvh.update_instances(&instances, &[0..instances.len()], 1.0);
+        assert_eq!(bvh.refits, 0);
+        assert_queries_match_scan(&bvh, &instances);
+    }
+
+    #[test]
+    fn appended_and_swap_removed_instances_update_the_hierarchy_in_place() {
+        let mut instances = random_instances(1000, 3);
+        let mut bvh = InstanceBvh::from_instances(&instances, 1.0).unwrap();
+        let nodes = bvh.nodes.len();
+
+        for instance in random_instances(100, 4) {
+            instances.push(instance);
+            bvh.update_instances(&instances, &[instances.len() - 1..instances.len()], 1.0);
+        }
+        assert_eq!(bvh.refits, 100);
+        assert!(bvh.nodes.len() > nodes, "full leaves are split");
+        assert_queries_match_scan(&bvh, &instances);
+
+        // Removes from the end and from the middle, emptying leaves.
+        for step in 0..300 {
+            let index = step * 37 % instances.len();
+            instances.swap_remove(index);
+            let dirty = if index < instances.len() { vec![index..index + 1] } else { Vec::new() };
+            bvh.update_instances(&instances, &dirty, 1.0);
+        }
+        assert!(bvh.refits > 400, "removing doesn't build the hierarchy again");
+        assert_queries_match_scan(&bvh, &instances);
+    }
+
+    const UNIT_BOX: BvhBounds = BvhBounds { min: Vec3::ZERO, max: Vec3::ONE };
+
+    #[test]
+    fn rays_enter_boxes_in_multiples_of_their_direction() {
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-2.0, 0.5, 0.5), Vec3::X), Some(2.0));
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-2.0, 0.5, 0.5), Vec3::X * 4.0), Some(0.5));
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-1.0, -1.0, -1.0), Vec3::ONE), Some(1.0));
+        // Starting inside.
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::splat(0.5), Vec3::NEG_Y), Some(0.0));
+    }
+
+    #[test]
+    fn rays_miss_boxes_beside_or_behind_them() {
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-2.0, 1.5, 0.5), Vec3::X), None);
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-2.0, 0.5, 0.5), Vec3::NEG_X), None);
+        assert_eq!(UNIT_BOX.ray_distance(Vec3::new(-2.0, -2.0, 0.5), Vec3::new(1.0, 0.2, 0.0)), None);
+    }
+}
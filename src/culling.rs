@@ -0,0 +1,747 @@
+use std::{marker::PhantomData, ops::Range};
+
+use bevy::{
+    prelude::*,
+    ecs::query::QueryItem,
+    math::Vec3A,
+    render::{
+        extract_component::ExtractComponent,
+        primitives::{Aabb, Frustum, Sphere},
+        renderer::{RenderDevice, RenderQueue},
+        render_phase::RenderPhase,
+        view::{ExtractedView, NoFrustumCulling},
+        Extract,
+    },
+    utils::{FloatOrd, HashMap, HashSet},
+    pbr::MeshUniform,
+    core_pipeline::core_3d::Transparent3d,
+};
+
+use crate::{
+    ExtractedInstanceLod, GpuCulling, GpuCullingPipeline, InstanceBuffer, InstanceBvh, InstanceData, InstanceDataVec,
+    InstanceStreams, ViewInstanceBuffers
+};
+
+/// Radius of a sphere around the origin enclosing the mesh of an instanced entity.
+#[derive(Component, Clone, Copy)]
+pub struct InstanceMeshRadius(pub f32);
+
+/// Largest scale `transform` applies along any of its axes.
+pub(crate) fn max_scale(transform: &Mat4) -> f32 {
+    transform.x_axis.truncate().length_squared()
+        .max(transform.y_axis.truncate().length_squared())
+        .max(transform.z_axis.truncate().length_squared())
+        .sqrt()
+}
+
+pub(crate) fn mesh_radius(mesh: &Mesh) -> Option<f32> {
+    mesh.compute_aabb().map(|aabb| aabb.center.length() + aabb.half_extents.length())
+}
+
+/// Local space bounds enclosing every instance, `None` without instances.
+fn instance_aabb<D: InstanceData>(instances: &[D], mesh_radius: f32) -> Option<Aabb> {
+    if instances.is_empty() {
+        return None;
+    }
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    for instance in instances {
+        let position = instance.position().unwrap_or_default();
+        let radius = instance.radius(mesh_radius);
+        min = min.min(position - radius);
+        max = max.max(position + radius);
+    }
+    Some(Aabb::from_min_max(min, max))
+}
+
+/// Replaces the mesh [`Aabb`] bevy's `calculate_bounds` gives an instanced entity with one
+/// enclosing every instance, so the entity stays visible while any of its instances is in view.
+///
+/// Only runs when [`InstanceData::position`] is implemented. The bounds are recomputed when the
+/// instances or the mesh change, entities with [`NoFrustumCulling`] are left alone.
+pub fn calculate_instance_bounds<D: InstanceData>(
+    mut commands: Commands,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    meshes: Res<Assets<Mesh>>,
+    mut query: Query<
+        (
+            Entity,
+            &Handle<Mesh>,
+            ChangeTrackers<Handle<Mesh>>,
+            &InstanceDataVec<D>,
+            ChangeTrackers<InstanceDataVec<D>>,
+            Option<&mut Aabb>,
+        ),
+        Without<NoFrustumCulling>,
+    >,
+) {
+    if D::zeroed().position().is_none() {
+        return;
+    }
+
+    let changed_meshes = mesh_events.iter()
+        .filter_map(|event| match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => Some(handle),
+            AssetEvent::Removed { .. } => None,
+        })
+        .collect::<HashSet<_>>();
+
+    for (entity, mesh_handle, mesh_tracker, instance_data, instance_tracker, aabb) in &mut query {
+        let changed = instance_tracker.is_changed()
+            || mesh_tracker.is_changed()
+            || changed_meshes.contains(mesh_handle);
+        if aabb.is_some() && !changed {
+            continue;
+        }
+        let instance_aabb = match meshes.get(mesh_handle)
+            .and_then(mesh_radius)
+            .and_then(|mesh_radius| instance_aabb(instance_data, mesh_radius))
+        {
+            Some(instance_aabb) => instance_aabb,
+            None => continue,
+        };
+        match aabb {
+            Some(mut aabb) => *aabb = instance_aabb,
+            // Inserted after the mesh bounds of `calculate_bounds`, which it overrides.
+            None => {
+                commands.entity(entity).insert(instance_aabb);
+            }
+        }
+    }
+}
+
+/// Extracts the mesh radius of visible instanced entities whose [`InstanceData::position`] or
+/// [`InstanceData::instance_bounds_wgsl`] is implemented.
+pub fn extract_instance_mesh_radii<D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    mut mesh_radii: Local<HashMap<Handle<Mesh>, f32>>,
+    mut mesh_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    meshes: Extract<Res<Assets<Mesh>>>,
+    query: Extract<Query<(Entity, &ComputedVisibility, &Handle<Mesh>), With<InstanceDataVec<D>>>>,
+) {
+    if D::zeroed().position().is_none() && D::instance_bounds_wgsl().is_none() {
+        return;
+    }
+
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                mesh_radii.remove(handle);
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, mesh_handle) in &query {
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+        let radius = match mesh_radii.get(mesh_handle) {
+            Some(radius) => *radius,
+            None => match meshes.get(mesh_handle).and_then(mesh_radius) {
+                Some(radius) => {
+                    mesh_radii.insert(mesh_handle.clone_weak(), radius);
+                    radius
+                }
+                None => continue,
+            },
+        };
+        values.push((entity, InstanceMeshRadius(radius)));
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+/// Restricts the instances of `D` a camera draws, e.g. a minimap only showing some of them.
+///
+/// Instances for which the function returns `false` are left out of the [`ViewInstanceBuffers`]
+/// of the camera's view, on top of frustum culling.
+#[derive(Component, Clone)]
+pub struct ViewInstanceFilter<D: InstanceData>(pub fn(&D) -> bool);
+
+impl<D: InstanceData> ExtractComponent for ViewInstanceFilter<D> {
+    type Query = &'static Self;
+    type Filter = With<Camera>;
+    type Out = Self;
+
+    fn extract_component(item: QueryItem<'_, Self::Query>) -> Option<Self> {
+        Some(item.clone())
+    }
+}
+
+/// Sorts the instances of an entity back to front in every view, so blending composes them in
+/// the right order.
+///
+/// Only applies to [`AlphaMode::Blend`] materials, and needs [`InstanceData::position`].
+#[derive(Component, Clone, Copy, Default)]
+pub struct SortInstances;
+
+/// Extracts [`SortInstances`] of visible instanced entities with a blended material.
+pub fn extract_sorted_instances<M: Material, D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    materials: Extract<Res<Assets<M>>>,
+    query: Extract<Query<
+        (Entity, &ComputedVisibility, &Handle<M>),
+        (With<SortInstances>, With<InstanceDataVec<D>>),
+    >>,
+) {
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, material_handle) in &query {
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+        if let Some(AlphaMode::Blend) = materials.get(material_handle).map(Material::alpha_mode) {
+            values.push((entity, SortInstances));
+        }
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+/// Local copy of the instances of an entity, and the instances drawn in each view.
+struct EntityInstances<D: InstanceData> {
+    len: usize,
+    /// Only kept for entities culled, filtered or sorted on the cpu, see
+    /// [`extract_culled_instances`].
+    instances: Vec<D>,
+    /// Whether `instances` mirrors the instance data of the entity.
+    copied: bool,
+    /// The ranges of `instances` updated this frame.
+    dirty: Vec<Range<usize>>,
+    /// Whether the entity had [`InstanceStreams`] when last culled, it then keeps no copy.
+    streams: bool,
+    /// World space bounds of every instance, recomputed each frame. With `bvh`, only the
+    /// instances found in view are.
+    spheres: Vec<Sphere>,
+    /// Hierarchy over the copied instances of entities with an [`InstanceBvh`], and the ranges
+    /// changed since it was updated.
+    bvh: Option<InstanceBvh>,
+    bvh_ranges: Vec<Range<usize>>,
+    views: HashMap<Entity, ViewInstances>,
+    /// The instances drawn in each view by each level of an [`InstanceLod`](crate::InstanceLod).
+    lods: Vec<HashMap<Entity, ViewInstances>>,
+}
+
+impl<D: InstanceData> Default for EntityInstances<D> {
+    fn default() -> Self {
+        EntityInstances {
+            len: 0,
+            instances: Vec::new(),
+            copied: false,
+            dirty: Vec::new(),
+            streams: false,
+            spheres: Vec::new(),
+            bvh: None,
+            bvh_ranges: Vec::new(),
+            views: HashMap::default(),
+            lods: Vec::new(),
+        }
+    }
+}
+
+impl<D: InstanceData> EntityInstances<D> {
+    /// Applies the dirty ranges of `instance_data` to the copy, or copies every instance when
+    /// there was no copy yet. With `bvh`, a hierarchy over them is kept too.
+    fn update(&mut self, instance_data: &InstanceDataVec<D>, added: bool, bvh: bool) {
+        let full = added || !self.copied;
+        self.dirty = if full {
+            vec![0..instance_data.len()]
+        } else {
+            instance_data.dirty_ranges()
+        };
+        self.instances.resize(instance_data.len(), D::zeroed());
+        for range in &self.dirty {
+            self.instances[range.clone()].copy_from_slice(&instance_data[range.clone()]);
+        }
+        self.copied = true;
+
+        if !bvh {
+            self.bvh = None;
+            self.bvh_ranges.clear();
+        } else if full || self.bvh.is_none() {
+            self.bvh = Some(InstanceBvh::default());
+            self.bvh_ranges.clear();
+        } else {
+            self.bvh_ranges.extend(self.dirty.iter().cloned());
+            // Past this point the hierarchy is built again anyway.
+            if self.bvh_ranges.iter().map(|range| range.len()).sum::<usize>() > self.instances.len() {
+                self.bvh = Some(InstanceBvh::default());
+                self.bvh_ranges.clear();
+            }
+        }
+    }
+
+    /// Drops the copy of the instances, and everything derived from it.
+    fn clear_copy(&mut self) {
+        if !self.copied {
+            return;
+        }
+        self.instances = Vec::new();
+        self.copied = false;
+        self.dirty = Vec::new();
+        self.spheres = Vec::new();
+        self.bvh = None;
+        self.bvh_ranges = Vec::new();
+    }
+
+    /// Brings the hierarchy over the copied instances up to date, if there is one.
+    fn update_bvh(&mut self, mesh_radius: f32) {
+        if let Some(bvh) = &mut self.bvh {
+            bvh.update_instances(&self.instances, &self.bvh_ranges, mesh_radius);
+            self.bvh_ranges.clear();
+        }
+    }
+
+    /// Computes the world space bounds of every instance, and the combined bounds of the entity.
+    ///
+    /// With a `bvh`, the combined bounds come from its root and the bounds of the instances are
+    /// left to [`cull_instances`].
+    fn compute_bounds(&mut self, transform: &Mat4, mesh_radius: f32) -> Sphere {
+        let scale = max_scale(transform);
+
+        if let Some(bounds) = self.bvh.as_ref().and_then(InstanceBvh::root_bounds) {
+            self.spheres.resize(self.instances.len(), Sphere { center: Vec3A::ZERO, radius: 0.0 });
+            return Sphere {
+                center: transform.transform_point3a(bounds.center().into()),
+                radius: (bounds.max - bounds.min).length() * 0.5 * scale,
+            };
+        }
+
+        let mut min = Vec3A::splat(f32::MAX);
+        let mut max = Vec3A::splat(f32::MIN);
+        self.spheres.clear();
+        for instance in &self.instances {
+            let sphere = instance_sphere(instance, transform, scale, mesh_radius);
+            min = min.min(sphere.center - sphere.radius);
+            max = max.max(sphere.center + sphere.radius);
+            self.spheres.push(sphere);
+        }
+
+        let aabb = Aabb::from_min_max(min.into(), max.into());
+        Sphere {
+            center: aabb.center,
+            radius: aabb.half_extents.length(),
+        }
+    }
+}
+
+/// World space bounds of `instance`, for an entity placed by `transform` scaling up to `scale`.
+fn instance_sphere<D: InstanceData>(instance: &D, transform: &Mat4, scale: f32, mesh_radius: f32) -> Sphere {
+    let position = instance.position().unwrap_or_default();
+    Sphere {
+        center: transform.transform_point3a(position.into()),
+        radius: instance.radius(mesh_radius) * scale,
+    }
+}
+
+/// Keeps the `visible` instances `filter` accepts and, with the third row of the view matrix,
+/// sorts them back to front by their world space `spheres`.
+fn filter_and_sort_instances<D: InstanceData>(
+    visible: &mut Vec<u32>,
+    instances: &[D],
+    spheres: &[Sphere],
+    filter: Option<fn(&D) -> bool>,
+    view_row_2: Option<Vec4>,
+) {
+    if let Some(filter) = filter {
+        visible.retain(|index| filter(&instances[*index as usize]));
+    }
+    if let Some(view_row_2) = view_row_2 {
+        // The view looks down -z, the farthest instance has the lowest z and comes first.
+        visible.sort_by_cached_key(|index| FloatOrd(view_row_2.dot(spheres[*index as usize].center.extend(1.0))));
+    }
+}
+
+/// The instances of an entity inside the frustum of a view, compacted into their own buffer.
+struct ViewInstances {
+    visible: Vec<u32>,
+    buffer: InstanceBuffer,
+}
+
+/// Uploads the `visible` instances of `view` and returns its buffer.
+///
+/// The whole buffer is only uploaded again when the visible instances or their order changed,
+/// otherwise only the slots of `dirty` instances are.
+fn write_view_instances<D: InstanceData>(
+    views: &mut HashMap<Entity, ViewInstances>,
+    view: Entity,
+    visible: Vec<u32>,
+    instances: &[D],
+    dirty: &[Range<usize>],
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+) -> InstanceBuffer {
+    let is_new = !views.contains_key(&view);
+    let view_instances = views.entry(view).or_insert_with(|| ViewInstances {
+        visible: Vec::new(),
+        buffer: InstanceBuffer::with_capacity::<D>(render_device, visible.len()),
+    });
+    if is_new || view_instances.visible != visible {
+        let data = visible.iter()
+            .map(|index| instances[*index as usize])
+            .collect::<Vec<_>>();
+        view_instances.buffer.write_all(render_device, render_queue, &data);
+        view_instances.visible = visible;
+    } else if !dirty.is_empty() {
+        // `dirty` is sorted and merged.
+        let is_dirty = |index: u32| {
+            let index = index as usize;
+            let range = dirty.partition_point(|range| range.end <= index);
+            dirty.get(range).map_or(false, |range| range.start <= index)
+        };
+        let mut slot = 0;
+        while slot < visible.len() {
+            if !is_dirty(visible[slot]) {
+                slot += 1;
+                continue;
+            }
+            let start = slot;
+            while slot < visible.len() && is_dirty(visible[slot]) {
+                slot += 1;
+            }
+            let data = visible[start..slot].iter()
+                .map(|index| instances[*index as usize])
+                .collect::<Vec<_>>();
+            render_queue.write_buffer(
+                &view_instances.buffer.buffer,
+                (start * std::mem::size_of::<D>()) as u64,
+                bytemuck::cast_slice(&data),
+            );
+        }
+    }
+    view_instances.buffer.clone()
+}
+
+/// Culling state kept alive across frames, keyed by the entity owning the instance data.
+#[derive(Resource)]
+pub struct CulledInstances<D: InstanceData> {
+    entities: HashMap<Entity, EntityInstances<D>>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> Default for CulledInstances<D> {
+    fn default() -> Self {
+        CulledInstances {
+            entities: HashMap::default(),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<D: InstanceData> CulledInstances<D> {
+    /// The views drawing a subset of the instances of `entity`, with the buffer of each subset.
+    pub(crate) fn view_buffers(&self, entity: Entity) -> impl Iterator<Item = (Entity, &InstanceBuffer)> {
+        self.entities.get(&entity)
+            .into_iter()
+            .flat_map(|entity_instances| entity_instances.views.iter())
+            .map(|(view, view_instances)| (*view, &view_instances.buffer))
+    }
+
+    /// Same as [`CulledInstances::view_buffers`], for a level of the
+    /// [`InstanceLod`](crate::InstanceLod) of `entity`.
+    pub(crate) fn lod_view_buffers(
+        &self,
+        entity: Entity,
+        level: usize,
+    ) -> impl Iterator<Item = (Entity, &InstanceBuffer)> {
+        self.entities.get(&entity)
+            .and_then(|entity_instances| entity_instances.lods.get(level))
+            .into_iter()
+            .flatten()
+            .map(|(view, view_instances)| (*view, &view_instances.buffer))
+    }
+}
+
+/// Keeps the state [`cull_instances`] needs for every entity with instances of `D`, including a
+/// copy of the instances of the entities culled, filtered or sorted on the cpu.
+///
+/// Nothing is copied when `D` has no [`InstanceData::position`] and no camera has a
+/// [`ViewInstanceFilter`], nor for entities with [`GpuCulling`] or [`InstanceStreams`]. A copy
+/// starts with every instance, then only takes the dirty ranges of the [`InstanceDataVec`].
+pub fn extract_culled_instances<D: InstanceData>(
+    mut culled_instances: ResMut<CulledInstances<D>>,
+    query: Extract<Query<(
+        Entity,
+        &InstanceDataVec<D>,
+        ChangeTrackers<InstanceDataVec<D>>,
+        Option<&InstanceBvh>,
+        Option<&GpuCulling>,
+    )>>,
+    filters: Extract<Query<(), (With<Camera>, With<ViewInstanceFilter<D>>)>>,
+    gpu_culling_pipeline: Option<Res<GpuCullingPipeline<D>>>,
+) {
+    let copy = D::zeroed().position().is_some() || !filters.is_empty();
+    // Only set up where storage buffers are supported.
+    let gpu_culling = gpu_culling_pipeline.is_some();
+
+    let mut previous = std::mem::take(&mut culled_instances.entities);
+    for (entity, instance_data, tracker, bvh, entity_gpu_culling) in &query {
+        let mut entity_instances = previous.remove(&entity).unwrap_or_default();
+        entity_instances.len = instance_data.len();
+        // Keep the copy in sync even while the entity is hidden.
+        if copy && !entity_instances.streams && !(gpu_culling && entity_gpu_culling.is_some()) {
+            entity_instances.update(instance_data, tracker.is_added(), bvh.is_some());
+        } else {
+            entity_instances.clear_copy();
+        }
+        culled_instances.entities.insert(entity, entity_instances);
+    }
+}
+
+pub(crate) fn view_frustum(view: &ExtractedView) -> Frustum {
+    let view_projection = view.projection * view.transform.compute_matrix().inverse();
+    // Instances are never tested against the far plane.
+    Frustum::from_view_projection(
+        &view_projection,
+        &view.transform.translation(),
+        &view.transform.back(),
+        0.0,
+    )
+}
+
+/// Selects the instances drawn in every view, shadow views included, and inserts them as
+/// [`ViewInstanceBuffers`].
+///
+/// When [`InstanceData::position`] is implemented the instances are culled against the frustum
+/// of each view, through a hierarchy like the [`InstanceBvh`] of entities having one, and entities
+/// with [`SortInstances`] get them sorted back to front in views with a transparent phase.
+/// Views with a [`ViewInstanceFilter`] only keep the instances it accepts. Other views draw the
+/// shared [`InstanceBuffer`]. The buffer of a view is uploaded again only when its instances or
+/// their order changed, otherwise only its changed instances are.
+///
+/// With an [`InstanceLod`](crate::InstanceLod), the instances past the distance of a level are
+/// moved to the [`ViewInstanceBuffers`] of the entity drawing that level.
+pub fn cull_instances<D: InstanceData>(
+    mut commands: Commands,
+    mut culled_instances: ResMut<CulledInstances<D>>,
+    instance_meshes: Query<(
+        &MeshUniform,
+        Option<&InstanceMeshRadius>,
+        Option<&SortInstances>,
+        Option<&GpuCulling>,
+        Option<&InstanceStreams>,
+        Option<&ExtractedInstanceLod>,
+    )>,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        Option<&ViewInstanceFilter<D>>,
+        Option<&RenderPhase<Transparent3d>>,
+    )>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let culling = D::zeroed().position().is_some();
+    let all_views = views.iter().map(|(view, ..)| view).collect::<Vec<_>>();
+    let views = views.iter()
+        .filter(|(_, _, filter, _)| culling || filter.is_some())
+        .map(|(view, extracted_view, filter, transparent_phase)| {
+            let frustum = culling.then(|| view_frustum(extracted_view));
+            // Only views drawing transparent items need sorted instances.
+            let view_row_2 = transparent_phase
+                .filter(|_| culling)
+                .map(|_| extracted_view.transform.compute_matrix().inverse().row(2));
+            let position = Vec3A::from(extracted_view.transform.translation());
+            (view, frustum, filter.map(|filter| filter.0), view_row_2, position)
+        })
+        .collect::<Vec<_>>();
+
+    for (entity, entity_instances) in &mut culled_instances.entities {
+        let entity = *entity;
+        let (mesh_uniform, mesh_radius, sort_instances, gpu_culling, instance_streams, lod) =
+            match instance_meshes.get(entity) {
+                Ok(instance_mesh) => instance_mesh,
+                Err(_) => continue,
+            };
+
+        entity_instances.streams = instance_streams.is_some();
+        if instance_streams.is_some() {
+            // Subsets of the instances would no longer line up with the other streams.
+            entity_instances.views.clear();
+            entity_instances.lods.clear();
+            continue;
+        }
+
+        if gpu_culling.is_some() {
+            // `queue_gpu_culling` fills the buffer of every view, its length is only known on the gpu.
+            entity_instances.views.retain(|view, _| all_views.contains(view));
+            let mut view_instance_buffers = ViewInstanceBuffers::default();
+            for view in &all_views {
+                let len = entity_instances.len;
+                let view_instances = entity_instances.views.entry(*view).or_insert_with(|| ViewInstances {
+                    visible: Vec::new(),
+                    buffer: InstanceBuffer::with_capacity::<D>(&render_device, len),
+                });
+                if view_instances.buffer.capacity < len {
+                    view_instances.buffer = InstanceBuffer::with_capacity::<D>(&render_device, len);
+                }
+                view_instances.visible.clear();
+                view_instances.buffer.length = 0;
+                view_instance_buffers.0.insert(*view, view_instances.buffer.clone());
+            }
+            commands.entity(entity).insert(view_instance_buffers);
+            continue;
+        }
+
+        if !entity_instances.copied {
+            // Nothing to cull, filter or sort, or the copy only starts next frame.
+            entity_instances.views.clear();
+            entity_instances.lods.clear();
+            continue;
+        }
+
+        let bounds = match (culling, mesh_radius) {
+            (true, Some(mesh_radius)) => {
+                entity_instances.update_bvh(mesh_radius.0);
+                Some(entity_instances.compute_bounds(&mesh_uniform.transform, mesh_radius.0))
+            }
+            // The mesh isn't loaded yet.
+            (true, None) => continue,
+            (false, _) => None,
+        };
+
+        entity_instances.views.retain(|view, _| views.iter().any(|(selected, ..)| selected == view));
+        let lod_levels = lod.map(|lod| lod.levels.as_slice()).unwrap_or_default();
+        entity_instances.lods.resize_with(lod_levels.len(), HashMap::default);
+        for lod_views in &mut entity_instances.lods {
+            lod_views.retain(|view, _| views.iter().any(|(selected, ..)| selected == view));
+        }
+
+        let mut view_instance_buffers = ViewInstanceBuffers::default();
+        let mut lod_view_instance_buffers = vec![ViewInstanceBuffers::default(); lod_levels.len()];
+        for (view, frustum, filter, view_row_2, position) in &views {
+            let mut visible = Vec::new();
+            match (frustum, &bounds) {
+                (Some(frustum), Some(bounds)) => {
+                    if frustum.intersects_sphere(bounds, false) {
+                        match &entity_instances.bvh {
+                            Some(bvh) => {
+                                let transform = &mesh_uniform.transform;
+                                let scale = max_scale(transform);
+                                let mesh_radius = mesh_radius.map_or(0.0, |mesh_radius| mesh_radius.0);
+                                let instances = &entity_instances.instances;
+                                let spheres = &mut entity_instances.spheres;
+                                bvh.query_frustum(frustum, transform, false, |index| {
+                                    let sphere =
+                                        instance_sphere(&instances[index], transform, scale, mesh_radius);
+                                    if frustum.intersects_sphere(&sphere, false) {
+                                        visible.push(index as u32);
+                                    }
+                                    spheres[index] = sphere;
+                                });
+                                // In the order of the instances, as without the hierarchy.
+                                visible.sort_unstable();
+                            }
+                            None => visible.extend(entity_instances.spheres.iter()
+                                .enumerate()
+                                .filter(|(_, sphere)| frustum.intersects_sphere(sphere, false))
+                                .map(|(index, _)| index as u32)),
+                        }
+                    }
+                }
+                _ => visible.extend(0..entity_instances.instances.len() as u32),
+            }
+            filter_and_sort_instances(
+                &mut visible,
+                &entity_instances.instances,
+                &entity_instances.spheres,
+                *filter,
+                view_row_2.filter(|_| sort_instances.is_some()),
+            );
+
+            let mut lod_visible = vec![Vec::new(); lod_levels.len()];
+            if let Some(lod) = lod {
+                let spheres = &entity_instances.spheres;
+                visible.retain(|index| {
+                    let distance = position.distance(spheres[*index as usize].center);
+                    match lod.level(distance) {
+                        Some(level) => {
+                            lod_visible[level].push(*index);
+                            false
+                        }
+                        None => true,
+                    }
+                });
+            }
+
+            let instances = &entity_instances.instances;
+            for (level, visible) in lod_visible.into_iter().enumerate() {
+                let buffer = write_view_instances(
+                    &mut entity_instances.lods[level],
+                    *view,
+                    visible,
+                    instances,
+                    &entity_instances.dirty,
+                    &render_device,
+                    &render_queue,
+                );
+                lod_view_instance_buffers[level].0.insert(*view, buffer);
+            }
+            let buffer = write_view_instances(
+                &mut entity_instances.views,
+                *view,
+                visible,
+                instances,
+                &entity_instances.dirty,
+                &render_device,
+                &render_queue,
+            );
+            view_instance_buffers.0.insert(*view, buffer);
+        }
+        commands.entity(entity).insert(view_instance_buffers);
+        for ((level_entity, _), view_instance_buffers) in lod_levels.iter().zip(lod_view_instance_buffers) {
+            commands.entity(*level_entity).insert(view_instance_buffers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StandardInstanceData;
+
+    fn instance(position: Vec3, scale: f32, value: f32) -> StandardInstanceData {
+        StandardInstanceData {
+            transform: Mat4::from_scale_rotation_translation(Vec3::splat(scale), Quat::IDENTITY, position),
+            color: Vec4::splat(value),
+        }
+    }
+
+    #[test]
+    fn instance_bounds_enclose_the_scaled_mesh_of_every_instance() {
+        assert!(instance_aabb::<StandardInstanceData>(&[], 1.0).is_none());
+
+        let instances = [instance(Vec3::ZERO, 1.0, 0.0), instance(Vec3::new(4.0, 0.0, 0.0), 2.0, 0.0)];
+        let aabb = instance_aabb(&instances, 1.0).unwrap();
+        assert_eq!(Vec3::from(aabb.min()), Vec3::splat(-1.0));
+        assert_eq!(Vec3::from(aabb.max()), Vec3::new(6.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn instances_are_filtered_then_sorted_back_to_front() {
+        // The second instance is filtered out.
+        let instances = [(-1.0, 1.0), (-3.0, 0.0), (-2.0, 1.0), (-4.0, 1.0)]
+            .map(|(z, value)| instance(Vec3::new(0.0, 0.0, z), 1.0, value));
+        let spheres = instances.iter()
+            .map(|instance| instance_sphere(instance, &Mat4::IDENTITY, 1.0, 1.0))
+            .collect::<Vec<_>>();
+        let filter: fn(&StandardInstanceData) -> bool = |instance| instance.color.x > 0.5;
+        // A view at the origin looking down -z.
+        let view_row_2 = Mat4::IDENTITY.inverse().row(2);
+
+        let mut visible = vec![0, 1, 2, 3];
+        filter_and_sort_instances(&mut visible, &instances, &spheres, Some(filter), None);
+        assert_eq!(visible, vec![0, 2, 3]);
+
+        let mut visible = vec![0, 1, 2, 3];
+        filter_and_sort_instances(&mut visible, &instances, &spheres, None, Some(view_row_2));
+        assert_eq!(visible, vec![3, 1, 2, 0]);
+
+        let mut visible = vec![0, 1, 2, 3];
+        filter_and_sort_instances(&mut visible, &instances, &spheres, Some(filter), Some(view_row_2));
+        assert_eq!(visible, vec![3, 2, 0]);
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        render_graph::{Node, NodeRunError, RenderGraphContext},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+            BindGroupLayoutEntry, BindingType, BufferBindingType, CachedComputePipelineId,
+            ComputePassDescriptor, ComputePipelineDescriptor, DynamicUniformBuffer, PipelineCache,
+            ShaderStages, ShaderType
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::ExtractedView,
+        Extract,
+    },
+    pbr::MeshUniform,
+};
+
+use crate::{
+    max_scale, view_frustum, CulledInstances, DrawInstancesIndirect, IndirectBuffers, InstanceBuffers,
+    InstanceData, InstanceDataVec, InstanceMeshRadius
+};
+
+/// Compute shader template of the [`GpuCulling`] pass, appended to
+/// [`InstanceData::instance_bounds_wgsl`].
+pub const INSTANCE_CULLING_WGSL: &str = include_str!("instance_culling.wgsl");
+
+/// Culls the instances of an entity against the frustum of every view in a compute pass, using
+/// [`InstanceData::instance_bounds_wgsl`], instead of on the cpu.
+///
+/// The survivors of each view are compacted into its [`ViewInstanceBuffers`](crate::ViewInstanceBuffers)
+/// and drawn indirectly, with the instance count written by the pass. [`ViewInstanceFilter`](crate::ViewInstanceFilter)
+/// and [`SortInstances`](crate::SortInstances) don't apply to these entities.
+///
+/// The pass needs storage buffers, where they are unsupported the entities are culled on the cpu.
+#[derive(Component, Clone, Copy, Default)]
+pub struct GpuCulling;
+
+/// Handle of the culling shader generated for `D`.
+pub fn gpu_culling_shader_handle<D: InstanceData>() -> HandleUntyped {
+    let mut hasher = DefaultHasher::new();
+    std::any::type_name::<D>().hash(&mut hasher);
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, hasher.finish())
+}
+
+/// Adds the culling shader of `D` to the shader assets, when `D` provides its bounds in WGSL.
+pub fn add_gpu_culling_shader<D: InstanceData>(app: &mut App) {
+    if let Some(instance_bounds) = D::instance_bounds_wgsl() {
+        let shader = Shader::from_wgsl(format!("{instance_bounds}\n{INSTANCE_CULLING_WGSL}"));
+        app.world.resource_mut::<Assets<Shader>>()
+            .set_untracked(gpu_culling_shader_handle::<D>(), shader);
+    }
+}
+
+/// Extracts [`GpuCulling`] of visible entities with instances of `D`, together with
+/// [`DrawInstancesIndirect`].
+pub fn extract_gpu_culled_instances<D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    query: Extract<Query<(Entity, &ComputedVisibility), (With<GpuCulling>, With<InstanceDataVec<D>>)>>,
+) {
+    if D::instance_bounds_wgsl().is_none() {
+        return;
+    }
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility) in &query {
+        if computed_visibility.is_visible() {
+            values.push((entity, (GpuCulling, DrawInstancesIndirect)));
+        }
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+#[derive(Clone, ShaderType)]
+pub struct InstanceCullingUniform {
+    pub planes: [Vec4; 5],
+    pub transform: Mat4,
+    pub scale: f32,
+    pub mesh_radius: f32,
+    pub instance_count: u32,
+    pub count_index: u32,
+}
+
+#[derive(Resource)]
+pub struct GpuCullingPipeline<D: InstanceData> {
+    pub layout: BindGroupLayout,
+    pub pipeline: CachedComputePipelineId,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> FromWorld for GpuCullingPipeline<D> {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_culling_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(InstanceCullingUniform::min_size()),
+                    },
+                    count: None,
+                },
+                storage_entry(1, true),
+                storage_entry(2, false),
+                storage_entry(3, false),
+            ],
+        });
+
+        let mut pipeline_cache = world.resource_mut::<PipelineCache>();
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("instance_culling_pipeline".into()),
+            layout: Some(vec![layout.clone()]),
+            shader: gpu_culling_shader_handle::<D>().typed(),
+            shader_defs: Vec::new(),
+            entry_point: "cull".into(),
+        });
+
+        GpuCullingPipeline {
+            layout,
+            pipeline,
+            _data: Default::default(),
+        }
+    }
+}
+
+struct GpuCullingDispatch {
+    bind_group: BindGroup,
+    uniform_offset: u32,
+    /// Workgroups along x and y, see [`workgroups`].
+    workgroups: (u32, u32),
+}
+
+/// Culling dispatches of the current frame, run by [`GpuCullingNode`].
+#[derive(Resource)]
+pub struct GpuCullingDispatches<D: InstanceData> {
+    uniforms: DynamicUniformBuffer<InstanceCullingUniform>,
+    dispatches: Vec<GpuCullingDispatch>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> Default for GpuCullingDispatches<D> {
+    fn default() -> Self {
+        GpuCullingDispatches {
+            uniforms: DynamicUniformBuffer::default(),
+            dispatches: Vec::new(),
+            _data: Default::default(),
+        }
+    }
+}
+
+const WORKGROUP_SIZE: usize = 64;
+
+/// Workgroups covering `instance_count` instances. Past the per dimension limit of the device
+/// they spill over to y, the shader rebuilds the linear index from both.
+fn workgroups(instance_count: usize, max_per_dimension: u32) -> (u32, u32) {
+    let total = ((instance_count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE) as u32;
+    let x = total.min(max_per_dimension.max(1));
+    (x, (total + x - 1) / x)
+}
+
+/// Prepares one culling dispatch per view for every entity with [`GpuCulling`].
+///
+/// Runs after [`prepare_indirect_buffers`](crate::prepare_indirect_buffers), which resets the
+/// instance count of each view the pass then increments.
+pub fn queue_gpu_culling<D: InstanceData>(
+    mut gpu_culling_dispatches: ResMut<GpuCullingDispatches<D>>,
+    gpu_culling_pipeline: Res<GpuCullingPipeline<D>>,
+    instance_buffers: Res<InstanceBuffers<D>>,
+    culled_instances: Res<CulledInstances<D>>,
+    indirect_buffers: Res<IndirectBuffers<D>>,
+    query: Query<(Entity, &MeshUniform, &InstanceMeshRadius), With<GpuCulling>>,
+    views: Query<&ExtractedView>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let GpuCullingDispatches { uniforms, dispatches, .. } = &mut *gpu_culling_dispatches;
+    uniforms.clear();
+    dispatches.clear();
+
+    let max_workgroups = render_device.limits().max_compute_workgroups_per_dimension;
+    let mut pending = Vec::new();
+    for (entity, mesh_uniform, mesh_radius) in &query {
+        let (instance_buffer, indirect_buffer) = match (
+            instance_buffers.buffers.get(&entity),
+            indirect_buffers.buffers.get(&entity),
+        ) {
+            (Some(instance_buffer), Some(indirect_buffer)) => (instance_buffer, indirect_buffer),
+            _ => continue,
+        };
+        if instance_buffer.length == 0 {
+            continue;
+        }
+
+        for (view, view_buffer) in culled_instances.view_buffers(entity) {
+            let extracted_view = match views.get(view) {
+                Ok(extracted_view) => extracted_view,
+                Err(_) => continue,
+            };
+            let frustum = view_frustum(extracted_view);
+            let uniform_offset = uniforms.push(InstanceCullingUniform {
+                planes: [0, 1, 2, 3, 4].map(|index| frustum.planes[index].normal_d()),
+                transform: mesh_uniform.transform,
+                scale: max_scale(&mesh_uniform.transform),
+                mesh_radius: mesh_radius.0,
+                instance_count: instance_buffer.length as u32,
+                // `instance_count` is the second field of both kinds of records.
+                count_index: (indirect_buffer.offset(view) / 4 + 1) as u32,
+            });
+            pending.push((
+                uniform_offset,
+                instance_buffer.buffer.clone(),
+                view_buffer.buffer.clone(),
+                indirect_buffer.buffer.clone(),
+                workgroups(instance_buffer.length, max_workgroups),
+            ));
+        }
+    }
+
+    uniforms.write_buffer(&render_device, &render_queue);
+    let uniform_binding = match uniforms.binding() {
+        Some(uniform_binding) => uniform_binding,
+        None => return,
+    };
+    for (uniform_offset, instances, visible_instances, indirect_args, workgroups) in pending {
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("instance_culling_bind_group"),
+            layout: &gpu_culling_pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_binding.clone(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: instances.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: visible_instances.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: indirect_args.as_entire_binding(),
+                },
+            ],
+        });
+        dispatches.push(GpuCullingDispatch {
+            bind_group,
+            uniform_offset,
+            workgroups,
+        });
+    }
+}
+
+/// Runs the culling dispatches of `D` before any view is drawn.
+pub struct GpuCullingNode<D: InstanceData>(PhantomData<D>);
+
+impl<D: InstanceData> Default for GpuCullingNode<D> {
+    fn default() -> Self {
+        GpuCullingNode(Default::default())
+    }
+}
+
+impl<D: InstanceData> GpuCullingNode<D> {
+    pub fn name() -> String {
+        format!("instance_culling_{}", std::any::type_name::<D>())
+    }
+}
+
+impl<D: InstanceData> Node for GpuCullingNode<D> {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let gpu_culling_dispatches = world.resource::<GpuCullingDispatches<D>>();
+        if gpu_culling_dispatches.dispatches.is_empty() {
+            return Ok(());
+        }
+        let pipeline_id = world.resource::<GpuCullingPipeline<D>>().pipeline;
+        let pipeline = match world.resource::<PipelineCache>().get_compute_pipeline(pipeline_id) {
+            Some(pipeline) => pipeline,
+            None => return Ok(()),
+        };
+
+        let mut pass = render_context.command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("instance_culling_pass"),
+        });
+        pass.set_pipeline(pipeline);
+        for dispatch in &gpu_culling_dispatches.dispatches {
+            pass.set_bind_group(0, &dispatch.bind_group, &[dispatch.uniform_offset]);
+            pass.dispatch_workgroups(dispatch.workgroups.0, dispatch.workgroups.1, 1);
+        }
+        Ok(())
+    }
+}
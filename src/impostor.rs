@@ -0,0 +1,461 @@
+use std::f32::consts::TAU;
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    asset::load_internal_asset,
+    ecs::system::{lifetimeless::Read, SystemParamItem},
+    render::{
+        camera::{RenderTarget, ScalingMode, Viewport},
+        mesh::{MeshVertexBufferLayout, VertexAttributeValues},
+        render_asset::RenderAssets,
+        render_phase::{
+            DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase, SetItemPipeline,
+            TrackedRenderPass
+        },
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+            BindGroupLayoutEntry, BindingResource, BindingType, Extent3d, PipelineCache, RenderPipelineDescriptor,
+            SamplerBindingType, ShaderRef, ShaderStages, SpecializedMeshPipeline, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType,
+            TextureUsages, TextureViewDimension, TextureViewId
+        },
+        renderer::RenderDevice,
+        view::{ExtractedView, RenderLayers, VisibleEntities},
+    },
+    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, NotShadowCaster, SetMeshBindGroup, SetMeshViewBindGroup},
+    core_pipeline::{clear_color::ClearColorConfig, core_3d::AlphaMask3d},
+    utils::HashMap,
+};
+
+use crate::{
+    push_instance_input, push_instance_stream_layouts, DrawMeshInstanced, ExtractedInstanceData,
+    ExtractedInstanceLod, InstanceData, InstancePipelineError, InstancePipelineKey, InstanceStorageLayout,
+    InstanceStreams, SetInstanceBindGroup, SpecializedInstancePipeline, SpecializedInstancePipelines
+};
+
+pub const IMPOSTOR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6410583427780531849);
+pub const IMPOSTOR_FRAGMENT_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 14302659271187436627);
+pub const INSTANCED_IMPOSTOR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 2870946135522173016);
+
+/// Loads the impostor shaders and cleans up finished [`bake_impostor`] calls. Added by
+/// [`InstanceMaterialPlugin`](crate::InstanceMaterialPlugin).
+pub struct ImpostorPlugin;
+
+impl Plugin for ImpostorPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(app, IMPOSTOR_SHADER_HANDLE, "impostor.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            IMPOSTOR_FRAGMENT_SHADER_HANDLE,
+            "impostor_fragment.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_system(finish_impostor_bakes);
+    }
+}
+
+/// A mesh baked by [`bake_impostor`]: the atlas of its frames, and the quad they are drawn on.
+#[derive(Clone)]
+pub struct Impostor {
+    pub atlas: Handle<Image>,
+    pub mesh: Handle<Mesh>,
+}
+
+/// Draws the instances of an entity farther than `distance` from a view as camera facing
+/// billboards of `impostor`, after the levels of its [`InstanceLod`](crate::InstanceLod).
+///
+/// Needs [`InstanceData::position`] and [`InstanceData::impostor_vertex_shader`]. The billboards
+/// are alpha masked, and cast no shadows.
+#[derive(Component, Clone)]
+pub struct InstanceImpostor {
+    pub impostor: Impostor,
+    pub distance: f32,
+}
+
+pub struct ImpostorBakeSettings {
+    /// Frames baked around the y axis of the mesh.
+    pub frames: u32,
+    /// Width and height of each frame, in pixels.
+    pub frame_size: u32,
+    /// Layer of the entities spawned to bake, which shouldn't be seen by any other camera.
+    pub render_layer: u8,
+    /// Frames the bake keeps rendering, so it's still there once its pipelines are compiled.
+    pub bake_frames: u32,
+}
+
+impl Default for ImpostorBakeSettings {
+    fn default() -> Self {
+        ImpostorBakeSettings {
+            frames: 8,
+            frame_size: 256,
+            render_layer: RenderLayers::TOTAL_LAYERS as u8 - 1,
+            bake_frames: 60,
+        }
+    }
+}
+
+/// Entity spawned by [`bake_impostor`], despawned after its last frame.
+#[derive(Component)]
+pub struct ImpostorBake {
+    remaining_frames: u32,
+}
+
+/// Renders `mesh` with `material` into a new atlas, once from each of `settings.frames` angles
+/// around its y axis, through orthographic cameras rendering to the atlas.
+///
+/// The mesh is baked as a regular, non instanced [`PbrBundle`], so `material` is a
+/// [`StandardMaterial`], e.g. the one wrapped by an
+/// [`InstancedStandardMaterial`](crate::InstancedStandardMaterial), whose own vertex shader
+/// needs instances.
+///
+/// The atlas is filled over the next frames, and the entities spawned for it are despawned after
+/// `settings.bake_frames`. Returns `None` until the mesh is loaded.
+pub fn bake_impostor(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    meshes: &mut Assets<Mesh>,
+    mesh_handle: &Handle<Mesh>,
+    material: &Handle<StandardMaterial>,
+    settings: &ImpostorBakeSettings,
+) -> Option<Impostor> {
+    let aabb = meshes.get(mesh_handle)?.compute_aabb()?;
+    let center = Vec3::from(aabb.center);
+    let radius = aabb.half_extents.length();
+
+    let size = Extent3d {
+        width: settings.frames * settings.frame_size,
+        height: settings.frame_size,
+        depth_or_array_layers: 1,
+    };
+    let mut atlas = Image {
+        texture_descriptor: TextureDescriptor {
+            label: Some("impostor_atlas"),
+            size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST | TextureUsages::RENDER_ATTACHMENT,
+        },
+        ..default()
+    };
+    atlas.resize(size);
+    let atlas = images.add(atlas);
+
+    // Frames are centered on the bounds of the mesh, the quad is moved up to match.
+    let mut quad = Mesh::from(shape::Quad::new(Vec2::splat(radius * 2.0)));
+    if let Some(VertexAttributeValues::Float32x3(positions)) = quad.attribute_mut(Mesh::ATTRIBUTE_POSITION) {
+        for position in positions {
+            position[1] += center.y;
+        }
+    }
+    let quad = meshes.add(quad);
+
+    let render_layers = RenderLayers::layer(settings.render_layer);
+    commands.spawn((
+        PbrBundle {
+            mesh: mesh_handle.clone(),
+            material: material.clone(),
+            ..default()
+        },
+        render_layers,
+        NotShadowCaster,
+        ImpostorBake { remaining_frames: settings.bake_frames },
+    ));
+    for frame in 0..settings.frames {
+        let angle = frame as f32 / settings.frames as f32 * TAU;
+        let direction = Vec3::new(angle.sin(), 0.0, angle.cos());
+        commands.spawn((
+            Camera3dBundle {
+                camera: Camera {
+                    viewport: Some(Viewport {
+                        physical_position: UVec2::new(frame * settings.frame_size, 0),
+                        physical_size: UVec2::splat(settings.frame_size),
+                        ..default()
+                    }),
+                    // Render in order, the first frame clears the whole atlas.
+                    priority: frame as isize - settings.frames as isize,
+                    target: RenderTarget::Image(atlas.clone()),
+                    ..default()
+                },
+                camera_3d: Camera3d {
+                    clear_color: if frame == 0 {
+                        ClearColorConfig::Custom(Color::NONE)
+                    } else {
+                        ClearColorConfig::None
+                    },
+                    ..default()
+                },
+                projection: Projection::Orthographic(OrthographicProjection {
+                    left: -radius,
+                    right: radius,
+                    bottom: -radius,
+                    top: radius,
+                    near: 0.0,
+                    far: radius * 4.0,
+                    scaling_mode: ScalingMode::None,
+                    ..default()
+                }),
+                transform: Transform::from_translation(center + direction * radius * 2.0)
+                    .looking_at(center, Vec3::Y),
+                ..default()
+            },
+            UiCameraConfig { show_ui: false },
+            render_layers,
+            ImpostorBake { remaining_frames: settings.bake_frames },
+        ));
+    }
+
+    Some(Impostor { atlas, mesh: quad })
+}
+
+pub fn finish_impostor_bakes(mut commands: Commands, mut bakes: Query<(Entity, &mut ImpostorBake)>) {
+    for (entity, mut bake) in &mut bakes {
+        bake.remaining_frames = bake.remaining_frames.saturating_sub(1);
+        if bake.remaining_frames == 0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Render world level entity drawing the billboards of an [`InstanceImpostor`].
+#[derive(Component)]
+pub struct ImpostorLevel {
+    pub atlas: Handle<Image>,
+}
+
+pub type DrawInstancedImpostor = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetImpostorBindGroup<1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    DrawMeshInstanced<true>,
+);
+
+/// bevy's mesh pipeline with the vertex shader of [`InstanceData::impostor_vertex_shader`], the
+/// impostor atlas and the instance buffer of `D`.
+#[derive(Resource)]
+pub struct InstanceImpostorPipeline<D: InstanceData> {
+    pub mesh_pipeline: MeshPipeline,
+    pub impostor_layout: BindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub instance_storage_layout: Option<BindGroupLayout>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> FromWorld for InstanceImpostorPipeline<D> {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let render_device = world.resource::<RenderDevice>();
+
+        let impostor_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("impostor_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    // The vertex shader reads the frame count from the size of the atlas.
+                    visibility: ShaderStages::VERTEX_FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        InstanceImpostorPipeline {
+            mesh_pipeline: world.resource::<MeshPipeline>().clone(),
+            impostor_layout,
+            vertex_shader: match D::impostor_vertex_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            instance_storage_layout: world.get_resource::<InstanceStorageLayout<D>>()
+                .map(|storage_layout| storage_layout.layout.clone()),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<D: InstanceData> SpecializedInstancePipeline for InstanceImpostorPipeline<D> {
+    type Key = InstancePipelineKey<MeshPipelineKey>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError> {
+        let mut descriptor = self.mesh_pipeline.specialize(key.key, layout)?;
+        if let Some(vertex_shader) = &self.vertex_shader {
+            descriptor.vertex.shader = vertex_shader.clone();
+        }
+        if let Some(fragment) = &mut descriptor.fragment {
+            fragment.shader = IMPOSTOR_FRAGMENT_SHADER_HANDLE.typed::<Shader>();
+        }
+        // Between the view and the mesh bind groups, where materials go.
+        descriptor.layout.get_or_insert_with(Vec::new).insert(1, self.impostor_layout.clone());
+        push_instance_input::<D>(&mut descriptor, self.instance_storage_layout.as_ref())?;
+        push_instance_stream_layouts(&mut descriptor, &key.stream_layouts)?;
+        descriptor.label = Some("instanced_impostor_pipeline".into());
+        Ok(descriptor)
+    }
+}
+
+#[derive(Component)]
+pub struct ImpostorBindGroup(pub BindGroup);
+
+/// Bind group of each impostor atlas, by the view of its texture, so it is created again when the
+/// atlas is.
+#[derive(Resource)]
+pub struct ImpostorBindGroups<D: InstanceData> {
+    bind_groups: HashMap<TextureViewId, BindGroup>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> Default for ImpostorBindGroups<D> {
+    fn default() -> Self {
+        ImpostorBindGroups {
+            bind_groups: Default::default(),
+            _data: Default::default(),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_instance_impostors<D: InstanceData>(
+    mut commands: Commands,
+    alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    instance_impostor_pipeline: Res<InstanceImpostorPipeline<D>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstanceImpostorPipeline<D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    mut impostor_bind_groups: ResMut<ImpostorBindGroups<D>>,
+    instance_meshes: Query<
+        (&MeshUniform, &ExtractedInstanceLod, Option<&InstanceStreams>),
+        With<ExtractedInstanceData<D>>,
+    >,
+    impostor_levels: Query<(&ImpostorLevel, &Handle<Mesh>)>,
+    mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<AlphaMask3d>)>,
+) {
+    if instance_impostor_pipeline.vertex_shader.is_none() {
+        return;
+    }
+
+    // Bind groups of atlases which were dropped or created again are dropped with `previous`.
+    let bind_groups = &mut impostor_bind_groups.bind_groups;
+    let mut previous = std::mem::take(bind_groups);
+    for (_, lod, _) in &instance_meshes {
+        for (level_entity, _) in &lod.levels {
+            let gpu_image = match impostor_levels.get(*level_entity)
+                .ok()
+                .and_then(|(impostor_level, _)| images.get(&impostor_level.atlas))
+            {
+                Some(gpu_image) => gpu_image,
+                None => continue,
+            };
+            let id = gpu_image.texture_view.id();
+            let bind_group = previous.remove(&id)
+                .or_else(|| bind_groups.get(&id).cloned())
+                .unwrap_or_else(|| render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("impostor_bind_group"),
+                    layout: &instance_impostor_pipeline.impostor_layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(&gpu_image.texture_view),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::Sampler(&gpu_image.sampler),
+                        },
+                    ],
+                }));
+            bind_groups.insert(id, bind_group.clone());
+            commands.entity(*level_entity).insert(ImpostorBindGroup(bind_group));
+        }
+    }
+
+    let draw_impostor = alpha_mask_draw_functions.read().id::<DrawInstancedImpostor>();
+    for (view, visible_entities, mut alpha_mask_phase) in &mut views {
+        let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples) | MeshPipelineKey::from_hdr(view.hdr);
+        let rangefinder = view.rangefinder3d();
+
+        for visible_entity in &visible_entities.entities {
+            let (mesh_uniform, lod, instance_streams) = match instance_meshes.get(*visible_entity) {
+                Ok(instance_mesh) => instance_mesh,
+                Err(_) => continue,
+            };
+            for (level_entity, _) in &lod.levels {
+                let mesh = match impostor_levels.get(*level_entity)
+                    .ok()
+                    .and_then(|(_, mesh_handle)| render_meshes.get(mesh_handle))
+                {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+
+                let mesh_key = MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
+                let pipeline_id = pipelines.specialize(
+                    &pipeline_cache,
+                    &instance_impostor_pipeline,
+                    InstancePipelineKey::new(mesh_key, instance_streams),
+                    &mesh.layout,
+                );
+                let pipeline_id = match pipeline_id {
+                    Some(id) => id,
+                    None => continue,
+                };
+
+                alpha_mask_phase.add(AlphaMask3d {
+                    entity: *level_entity,
+                    draw_function: draw_impostor,
+                    pipeline: pipeline_id,
+                    distance: rangefinder.distance(&mesh_uniform.transform),
+                });
+            }
+        }
+    }
+}
+
+pub struct SetImpostorBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetImpostorBindGroup<I> {
+    type Param = ();
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Option<Read<ImpostorBindGroup>>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        impostor_bind_group: Option<&'w ImpostorBindGroup>,
+        _param: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        match impostor_bind_group {
+            Some(impostor_bind_group) => {
+                pass.set_bind_group(I, &impostor_bind_group.0, &[]);
+                RenderCommandResult::Success
+            }
+            // The atlas isn't loaded yet.
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
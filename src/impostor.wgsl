@@ -0,0 +1,45 @@
+#define_import_path bevy_instancing::impostor
+
+@group(1) @binding(0)
+var impostor_atlas: texture_2d<f32>;
+@group(1) @binding(1)
+var impostor_sampler: sampler;
+
+struct ImpostorVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+struct ImpostorVertex {
+    world_position: vec4<f32>,
+    uv: vec2<f32>,
+};
+
+// Turns a vertex of the impostor quad into a billboard at the origin of `model`, facing the camera
+// around the y axis, and maps its uv to the atlas frame baked from the closest angle.
+fn impostor_vertex(
+    model: mat4x4<f32>,
+    vertex_position: vec3<f32>,
+    uv: vec2<f32>,
+    camera_position: vec3<f32>
+) -> ImpostorVertex {
+    let center = (model * vec4<f32>(0.0, 0.0, 0.0, 1.0)).xyz;
+    let scale = length(model[1].xyz);
+    let up = vec3<f32>(0.0, 1.0, 0.0);
+    let to_camera = normalize((camera_position - center) * vec3<f32>(1.0, 0.0, 1.0));
+    let right = normalize(cross(up, to_camera));
+
+    var out: ImpostorVertex;
+    out.world_position = vec4<f32>(center + (right * vertex_position.x + up * vertex_position.y) * scale, 1.0);
+
+    // Frames are baked around the y axis of the mesh, so the angle is taken in the space of the
+    // instance. Assumes a uniform scale.
+    let local_to_camera = transpose(mat3x3<f32>(model[0].xyz, model[1].xyz, model[2].xyz)) * to_camera;
+    let atlas_size = textureDimensions(impostor_atlas);
+    let frames = u32(atlas_size.x / atlas_size.y);
+    let angle = atan2(local_to_camera.x, local_to_camera.z);
+    let frame = u32(round(angle / 6.28318530718 * f32(frames)) + f32(frames)) % frames;
+    out.uv = vec2<f32>((f32(frame) + uv.x) / f32(frames), uv.y);
+    return out;
+}
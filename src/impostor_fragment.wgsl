@@ -0,0 +1,11 @@
+#import bevy_instancing::impostor
+
+@fragment
+fn fragment(in: ImpostorVertexOutput) -> @location(0) vec4<f32> {
+    // Lighting and tonemapping are baked into the atlas.
+    let color = textureSample(impostor_atlas, impostor_sampler, in.uv) * in.color;
+    if (color.a < 0.5) {
+        discard;
+    }
+    return color;
+}
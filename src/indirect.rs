@@ -0,0 +1,194 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::GpuBufferInfo,
+        render_asset::RenderAssets,
+        render_resource::{Buffer, BufferDescriptor, BufferUsages},
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::HashMap,
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{CulledInstances, ExtractedInstanceData, InstanceBuffers, InstanceData, InstanceDataVec};
+
+/// Draws the instances of an entity with `draw_indexed_indirect` or `draw_indirect`, reading
+/// the instance count from an [`IndirectBuffer`] instead of passing it from the cpu.
+#[derive(Component, Clone, Copy, Default)]
+pub struct DrawInstancesIndirect;
+
+/// Arguments of an indexed indirect draw, as laid out in the indirect buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DrawIndexedIndirectArgs {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub first_instance: u32,
+}
+
+/// Arguments of a non-indexed indirect draw, as laid out in the indirect buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct DrawIndirectArgs {
+    pub vertex_count: u32,
+    pub instance_count: u32,
+    pub first_vertex: u32,
+    pub first_instance: u32,
+}
+
+/// Indirect draw records of an entity.
+///
+/// The first record draws the whole [`InstanceBuffer`](crate::InstanceBuffer), the others draw
+/// the [`ViewInstanceBuffers`](crate::ViewInstanceBuffers) of a view. They are written by
+/// [`prepare_indirect_buffers`], a compute pass can overwrite their instance count afterwards.
+#[derive(Component, Clone)]
+pub struct IndirectBuffer {
+    pub buffer: Buffer,
+    /// Whether the records are [`DrawIndexedIndirectArgs`] or [`DrawIndirectArgs`].
+    pub indexed: bool,
+    /// Byte offset of the record of each view with its own instances.
+    pub view_offsets: HashMap<Entity, u64>,
+    capacity: usize,
+}
+
+impl IndirectBuffer {
+    fn with_capacity(render_device: &RenderDevice, capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instance_indirect_buffer"),
+            // Sized for the larger record, so the mesh may switch between indexed and not.
+            size: (capacity * std::mem::size_of::<DrawIndexedIndirectArgs>()) as u64,
+            usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        IndirectBuffer {
+            buffer,
+            indexed: true,
+            view_offsets: HashMap::default(),
+            capacity,
+        }
+    }
+
+    /// Byte offset of the record to draw in `view`.
+    pub fn offset(&self, view: Entity) -> u64 {
+        self.view_offsets.get(&view).copied().unwrap_or(0)
+    }
+}
+
+/// Indirect buffers kept alive across frames, keyed by the entity owning the instance data.
+#[derive(Resource)]
+pub struct IndirectBuffers<D: InstanceData> {
+    buffers: HashMap<Entity, IndirectBuffer>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> Default for IndirectBuffers<D> {
+    fn default() -> Self {
+        IndirectBuffers {
+            buffers: HashMap::default(),
+            _data: Default::default(),
+        }
+    }
+}
+
+/// Extracts [`DrawInstancesIndirect`] of visible entities with instances of `D`.
+pub fn extract_indirect_instances<D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    query: Extract<Query<
+        (Entity, &ComputedVisibility),
+        (With<DrawInstancesIndirect>, With<InstanceDataVec<D>>),
+    >>,
+) {
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility) in &query {
+        if computed_visibility.is_visible() {
+            values.push((entity, DrawInstancesIndirect));
+        }
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+fn draw_args(buffer_info: &GpuBufferInfo, instance_counts: &[usize]) -> Vec<u8> {
+    match buffer_info {
+        GpuBufferInfo::Indexed { count, .. } => {
+            let args = instance_counts.iter()
+                .map(|instance_count| DrawIndexedIndirectArgs {
+                    index_count: *count,
+                    instance_count: *instance_count as u32,
+                    ..default()
+                })
+                .collect::<Vec<_>>();
+            bytemuck::cast_slice(&args).to_vec()
+        }
+        GpuBufferInfo::NonIndexed { vertex_count } => {
+            let args = instance_counts.iter()
+                .map(|instance_count| DrawIndirectArgs {
+                    vertex_count: *vertex_count,
+                    instance_count: *instance_count as u32,
+                    ..default()
+                })
+                .collect::<Vec<_>>();
+            bytemuck::cast_slice(&args).to_vec()
+        }
+    }
+}
+
+/// Writes the indirect draw records of entities with [`DrawInstancesIndirect`], from the
+/// instance counts known on the cpu.
+pub fn prepare_indirect_buffers<D: InstanceData>(
+    mut commands: Commands,
+    mut indirect_buffers: ResMut<IndirectBuffers<D>>,
+    instance_buffers: Res<InstanceBuffers<D>>,
+    culled_instances: Res<CulledInstances<D>>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    query: Query<(Entity, &Handle<Mesh>), (With<DrawInstancesIndirect>, With<ExtractedInstanceData<D>>)>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    indirect_buffers.buffers.retain(|entity, _| query.contains(*entity));
+
+    for (entity, mesh_handle) in &query {
+        let (gpu_mesh, instance_buffer) = match (
+            render_meshes.get(mesh_handle),
+            instance_buffers.buffers.get(&entity),
+        ) {
+            (Some(gpu_mesh), Some(instance_buffer)) => (gpu_mesh, instance_buffer),
+            _ => continue,
+        };
+        let indexed = matches!(gpu_mesh.buffer_info, GpuBufferInfo::Indexed { .. });
+        let record_size = if indexed {
+            std::mem::size_of::<DrawIndexedIndirectArgs>()
+        } else {
+            std::mem::size_of::<DrawIndirectArgs>()
+        };
+
+        let mut view_offsets = HashMap::default();
+        let mut instance_counts = vec![instance_buffer.length];
+        for (view, view_buffer) in culled_instances.view_buffers(entity) {
+            view_offsets.insert(view, (instance_counts.len() * record_size) as u64);
+            instance_counts.push(view_buffer.length);
+        }
+
+        let indirect_buffer = indirect_buffers.buffers
+            .entry(entity)
+            .or_insert_with(|| IndirectBuffer::with_capacity(&render_device, instance_counts.len()));
+        if instance_counts.len() > indirect_buffer.capacity {
+            *indirect_buffer = IndirectBuffer::with_capacity(&render_device, instance_counts.len());
+        }
+        indirect_buffer.indexed = indexed;
+        indirect_buffer.view_offsets = view_offsets;
+        render_queue.write_buffer(
+            &indirect_buffer.buffer,
+            0,
+            &draw_args(&gpu_mesh.buffer_info, &instance_counts),
+        );
+        commands.entity(entity).insert(indirect_buffer.clone());
+    }
+}
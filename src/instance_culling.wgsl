@@ -0,0 +1,47 @@
+// Appended to the `InstanceData::instance_bounds_wgsl` of the instance type, which declares
+// `Instance` and `instance_bounds`.
+
+struct InstanceCulling {
+    // Left, right, top, bottom and near planes of the view frustum, in world space.
+    planes: array<vec4<f32>, 5>,
+    transform: mat4x4<f32>,
+    scale: f32,
+    mesh_radius: f32,
+    instance_count: u32,
+    // Index of the `instance_count` of the indirect draw record of the view.
+    count_index: u32,
+};
+
+@group(0) @binding(0)
+var<uniform> culling: InstanceCulling;
+@group(0) @binding(1)
+var<storage, read> instances: array<Instance>;
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<Instance>;
+@group(0) @binding(3)
+var<storage, read_write> indirect_args: array<atomic<u32>>;
+
+@compute @workgroup_size(64)
+fn cull(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // Large dispatches spill over to y, past the per dimension workgroup limit.
+    let index = global_id.x + global_id.y * num_workgroups.x * 64u;
+    if (index >= culling.instance_count) {
+        return;
+    }
+
+    let instance = instances[index];
+    let bounds = instance_bounds(instance, culling.mesh_radius);
+    let center = culling.transform * vec4<f32>(bounds.xyz, 1.0);
+    let radius = bounds.w * culling.scale;
+    for (var i = 0u; i < 5u; i = i + 1u) {
+        if (dot(culling.planes[i], center) + radius <= 0.0) {
+            return;
+        }
+    }
+
+    let slot = atomicAdd(&indirect_args[culling.count_index], 1u);
+    visible_instances[slot] = instance;
+}
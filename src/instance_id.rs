@@ -0,0 +1,258 @@
+use bevy::prelude::*;
+
+use crate::{InstanceData, InstanceDataVec};
+
+/// Stable handle to an instance added through [`InstanceIds`], valid until it is removed.
+///
+/// Ids of removed instances are reused, with a new generation so stale ids don't match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceId {
+    slot: u32,
+    generation: u32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct IdSlot {
+    generation: u32,
+    index: Option<usize>,
+}
+
+/// Hands out [`InstanceId`]s for the instances of the [`InstanceDataVec`] next to it, so
+/// instances can be referred to over time while others are added and removed.
+///
+/// Removing an instance swap removes it, only the last instance moves and only its index is
+/// uploaded again. The index of an instance is also its slot in the instance buffer of the
+/// entity, e.g. the instance index returned by picking.
+///
+/// Once in use, instances must only be added and removed through [`InstanceIds`]: the methods of
+/// [`InstanceDataVec`] moving instances around aren't tracked. Overwriting instances in place
+/// is fine. Once their count no longer matches the ids, adding and removing instances panics and
+/// looking them up returns `None`.
+#[derive(Component, Clone, Default)]
+pub struct InstanceIds {
+    slots: Vec<IdSlot>,
+    free: Vec<u32>,
+    /// Id of the instance at each index.
+    ids: Vec<InstanceId>,
+}
+
+impl InstanceIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes `data` to `instances`, returning its id.
+    pub fn push<D: InstanceData>(&mut self, instances: &mut InstanceDataVec<D>, data: D) -> InstanceId {
+        self.assert_in_sync(instances);
+        let index = instances.len();
+        instances.push(data);
+
+        let id = match self.free.pop() {
+            Some(slot) => {
+                let id_slot = &mut self.slots[slot as usize];
+                id_slot.index = Some(index);
+                InstanceId { slot, generation: id_slot.generation }
+            }
+            None => {
+                self.slots.push(IdSlot { generation: 0, index: Some(index) });
+                InstanceId { slot: self.slots.len() as u32 - 1, generation: 0 }
+            }
+        };
+        self.ids.push(id);
+        id
+    }
+
+    /// Swap removes the instance of `id` from `instances`, `None` if it was already removed.
+    pub fn remove<D: InstanceData>(
+        &mut self,
+        instances: &mut InstanceDataVec<D>,
+        id: InstanceId,
+    ) -> Option<D> {
+        self.assert_in_sync(instances);
+        let index = self.index(id)?;
+        let data = instances.swap_remove(index);
+        self.ids.swap_remove(index);
+        if let Some(moved) = self.ids.get(index) {
+            self.slots[moved.slot as usize].index = Some(index);
+        }
+
+        let id_slot = &mut self.slots[id.slot as usize];
+        id_slot.index = None;
+        id_slot.generation = id_slot.generation.wrapping_add(1);
+        self.free.push(id.slot);
+        Some(data)
+    }
+
+    /// Removes every instance from `instances`, invalidating every id.
+    pub fn clear<D: InstanceData>(&mut self, instances: &mut InstanceDataVec<D>) {
+        instances.clear();
+        for id in self.ids.drain(..) {
+            let id_slot = &mut self.slots[id.slot as usize];
+            id_slot.index = None;
+            id_slot.generation = id_slot.generation.wrapping_add(1);
+            self.free.push(id.slot);
+        }
+    }
+
+    /// Current index of the instance of `id`, `None` once removed.
+    pub fn index(&self, id: InstanceId) -> Option<usize> {
+        self.slots.get(id.slot as usize)
+            .filter(|id_slot| id_slot.generation == id.generation)
+            .and_then(|id_slot| id_slot.index)
+    }
+
+    /// Id of the instance at `index`.
+    pub fn id(&self, index: usize) -> Option<InstanceId> {
+        self.ids.get(index).copied()
+    }
+
+    pub fn contains(&self, id: InstanceId) -> bool {
+        self.index(id).is_some()
+    }
+
+    /// The instance of `id`, `None` once removed or when `instances` are out of sync with the ids.
+    pub fn get<'a, D: InstanceData>(
+        &self,
+        instances: &'a InstanceDataVec<D>,
+        id: InstanceId,
+    ) -> Option<&'a D> {
+        if !self.in_sync(instances) {
+            return None;
+        }
+        instances.get(self.index(id)?)
+    }
+
+    /// Mutable access to the instance of `id`, marking only that instance as dirty. `None` once
+    /// removed or when `instances` are out of sync with the ids.
+    pub fn get_mut<'a, D: InstanceData>(
+        &self,
+        instances: &'a mut InstanceDataVec<D>,
+        id: InstanceId,
+    ) -> Option<&'a mut D> {
+        if !self.in_sync(instances) {
+            return None;
+        }
+        instances.get_mut(self.index(id)?)
+    }
+
+    /// Ids of the instances, in the order of their indices.
+    pub fn ids(&self) -> &[InstanceId] {
+        &self.ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Instances added or removed behind the back of the ids would make them point to the wrong
+    /// instances, or to none.
+    fn in_sync<D: InstanceData>(&self, instances: &InstanceDataVec<D>) -> bool {
+        instances.len() == self.ids.len()
+    }
+
+    fn assert_in_sync<D: InstanceData>(&self, instances: &InstanceDataVec<D>) {
+        assert!(self.in_sync(instances), "instances were added or removed without their InstanceIds");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StandardInstanceData;
+
+    fn instance(value: f32) -> StandardInstanceData {
+        StandardInstanceData {
+            transform: Mat4::IDENTITY,
+            color: Vec4::splat(value),
+        }
+    }
+
+    fn value(instances: &InstanceDataVec<StandardInstanceData>, ids: &InstanceIds, id: InstanceId) -> Option<f32> {
+        ids.get(instances, id).map(|instance| instance.color.x)
+    }
+
+    #[test]
+    fn ids_follow_their_instances() {
+        let mut instances = InstanceDataVec::new();
+        let mut ids = InstanceIds::new();
+        let a = ids.push(&mut instances, instance(0.0));
+        let b = ids.push(&mut instances, instance(1.0));
+        let c = ids.push(&mut instances, instance(2.0));
+
+        assert_eq!(ids.remove(&mut instances, b).map(|instance| instance.color.x), Some(1.0));
+        assert_eq!(ids.len(), 2);
+        assert_eq!(value(&instances, &ids, a), Some(0.0));
+        assert_eq!(value(&instances, &ids, b), None);
+        assert_eq!(value(&instances, &ids, c), Some(2.0));
+        assert!(ids.remove(&mut instances, b).is_none());
+    }
+
+    #[test]
+    fn removing_moves_the_last_instance() {
+        let mut instances = InstanceDataVec::new();
+        let mut ids = InstanceIds::new();
+        let a = ids.push(&mut instances, instance(0.0));
+        let b = ids.push(&mut instances, instance(1.0));
+        let c = ids.push(&mut instances, instance(2.0));
+
+        ids.remove(&mut instances, a);
+        assert_eq!(ids.index(c), Some(0));
+        assert_eq!(ids.index(b), Some(1));
+        assert_eq!(ids.ids(), &[c, b]);
+        assert_eq!(ids.id(0), Some(c));
+
+        // Removing the last instance moves nothing.
+        ids.remove(&mut instances, b);
+        assert_eq!(ids.index(c), Some(0));
+        assert_eq!(ids.ids(), &[c]);
+        assert_eq!(instances.len(), 1);
+    }
+
+    #[test]
+    fn reused_slots_invalidate_stale_ids() {
+        let mut instances = InstanceDataVec::new();
+        let mut ids = InstanceIds::new();
+        let a = ids.push(&mut instances, instance(0.0));
+        ids.remove(&mut instances, a);
+        let b = ids.push(&mut instances, instance(1.0));
+
+        assert_ne!(a, b);
+        assert!(!ids.contains(a));
+        assert_eq!(value(&instances, &ids, a), None);
+        assert_eq!(value(&instances, &ids, b), Some(1.0));
+        assert!(ids.get_mut(&mut instances, a).is_none());
+
+        ids.clear(&mut instances);
+        assert!(!ids.contains(b));
+        assert!(instances.is_empty());
+        let c = ids.push(&mut instances, instance(2.0));
+        assert_eq!(ids.index(c), Some(0));
+        assert!(!ids.contains(b));
+    }
+
+    #[test]
+    fn instances_added_behind_the_ids_are_not_looked_up() {
+        let mut instances = InstanceDataVec::new();
+        let mut ids = InstanceIds::new();
+        let a = ids.push(&mut instances, instance(0.0));
+        instances.push(instance(1.0));
+
+        assert_eq!(value(&instances, &ids, a), None);
+        assert!(ids.get_mut(&mut instances, a).is_none());
+        assert_eq!(ids.index(a), Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "without their InstanceIds")]
+    fn instances_added_behind_the_ids_panic_on_push() {
+        let mut instances = InstanceDataVec::new();
+        let mut ids = InstanceIds::new();
+        ids.push(&mut instances, instance(0.0));
+        instances.push(instance(1.0));
+        ids.push(&mut instances, instance(2.0));
+    }
+}
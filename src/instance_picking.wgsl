@@ -0,0 +1,20 @@
+struct InstancePicking {
+    id: u32,
+};
+
+// After the instance storage buffer, when the instances are bound as one.
+#ifdef INSTANCE_STORAGE_BUFFER
+@group(4) @binding(0)
+#else
+@group(3) @binding(0)
+#endif
+var<uniform> instance_picking: InstancePicking;
+
+struct FragmentInput {
+    @location(9) @interpolate(flat) instance_index: u32,
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec2<u32> {
+    return vec2<u32>(instance_picking.id, in.instance_index);
+}
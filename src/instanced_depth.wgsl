@@ -0,0 +1,72 @@
+#import bevy_pbr::mesh_view_types
+#import bevy_pbr::mesh_types
+
+@group(0) @binding(0)
+var<uniform> view: View;
+
+@group(1) @binding(0)
+var<uniform> mesh: Mesh;
+
+#ifdef SKINNED
+@group(1) @binding(1)
+var<uniform> joint_matrices: SkinnedMesh;
+#import bevy_pbr::skinning
+#endif
+
+// NOTE: Bindings must come before functions that use them!
+#import bevy_pbr::mesh_functions
+
+#ifdef INSTANCE_STORAGE_BUFFER
+// The layout of `StandardInstanceData`, instance types with another layout need their own shader.
+struct Instance {
+    transform: mat4x4<f32>,
+    color: vec4<f32>,
+};
+
+@group(2) @binding(0)
+var<storage, read> instances: array<Instance>;
+#endif
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+#ifdef SKINNED
+    @location(4) joint_indices: vec4<u32>,
+    @location(5) joint_weights: vec4<f32>,
+#endif
+#ifdef INSTANCE_STORAGE_BUFFER
+    @builtin(instance_index) instance_index: u32,
+#else
+    @location(8) i_transform_0: vec4<f32>,
+    @location(9) i_transform_1: vec4<f32>,
+    @location(10) i_transform_2: vec4<f32>,
+    @location(11) i_transform_3: vec4<f32>,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+#ifdef SKINNED
+    var model = skin_model(vertex.joint_indices, vertex.joint_weights);
+#else
+    var model = mesh.model;
+#endif
+#ifdef INSTANCE_STORAGE_BUFFER
+    let instance_transform = instances[vertex.instance_index].transform;
+#else
+    let instance_transform = mat4x4<f32>(
+        vertex.i_transform_0,
+        vertex.i_transform_1,
+        vertex.i_transform_2,
+        vertex.i_transform_3,
+    );
+#endif
+    model = model * instance_transform;
+
+    var out: VertexOutput;
+    out.clip_position = mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
+    return out;
+}
@@ -0,0 +1,51 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+#import bevy_instancing::impostor
+
+#ifdef INSTANCE_STORAGE_BUFFER
+struct Instance {
+    transform: mat4x4<f32>,
+    color: vec4<f32>,
+};
+
+@group(3) @binding(0)
+var<storage, read> instances: array<Instance>;
+#endif
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+#ifdef INSTANCE_STORAGE_BUFFER
+    @builtin(instance_index) instance_index: u32,
+#else
+    @location(8) i_transform_0: vec4<f32>,
+    @location(9) i_transform_1: vec4<f32>,
+    @location(10) i_transform_2: vec4<f32>,
+    @location(11) i_transform_3: vec4<f32>,
+    @location(12) i_color: vec4<f32>,
+#endif
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> ImpostorVertexOutput {
+#ifdef INSTANCE_STORAGE_BUFFER
+    let instance_transform = instances[vertex.instance_index].transform;
+    let instance_color = instances[vertex.instance_index].color;
+#else
+    let instance_transform = mat4x4<f32>(
+        vertex.i_transform_0,
+        vertex.i_transform_1,
+        vertex.i_transform_2,
+        vertex.i_transform_3,
+    );
+    let instance_color = vertex.i_color;
+#endif
+    let model = mesh.model * instance_transform;
+    let impostor = impostor_vertex(model, vertex.position, vertex.uv, view.world_position);
+
+    var out: ImpostorVertexOutput;
+    out.clip_position = view.view_proj * impostor.world_position;
+    out.uv = impostor.uv;
+    out.color = instance_color;
+    return out;
+}
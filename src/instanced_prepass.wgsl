@@ -0,0 +1,103 @@
+#import bevy_pbr::prepass_bindings
+#import bevy_pbr::mesh_functions
+
+#ifdef INSTANCE_STORAGE_BUFFER
+struct Instance {
+    transform: mat4x4<f32>,
+    color: vec4<f32>,
+};
+
+@group(3) @binding(0)
+var<storage, read> instances: array<Instance>;
+#endif // INSTANCE_STORAGE_BUFFER
+
+// Same inputs and outputs as bevy's prepass.wgsl, plus the `StandardInstanceData` attributes.
+struct Vertex {
+    @location(0) position: vec3<f32>,
+
+#ifdef VERTEX_UVS
+    @location(1) uv: vec2<f32>,
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+    @location(2) normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+
+#ifdef SKINNED
+    @location(4) joint_indices: vec4<u32>,
+    @location(5) joint_weights: vec4<f32>,
+#endif // SKINNED
+
+#ifdef INSTANCE_STORAGE_BUFFER
+    @builtin(instance_index) instance_index: u32,
+#else
+    @location(8) i_transform_0: vec4<f32>,
+    @location(9) i_transform_1: vec4<f32>,
+    @location(10) i_transform_2: vec4<f32>,
+    @location(11) i_transform_3: vec4<f32>,
+#endif // INSTANCE_STORAGE_BUFFER
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#ifdef VERTEX_TANGENTS
+    @location(2) world_tangent: vec4<f32>,
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+}
+
+// Proportional to the inverse transpose of the upper 3x3 of `model`, which is all the normals
+// need since they are normalized afterwards.
+fn instance_normal_matrix(model: mat4x4<f32>) -> mat3x3<f32> {
+    let x = model[0].xyz;
+    let y = model[1].xyz;
+    let z = model[2].xyz;
+    return mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y)) * sign(determinant(mat3x3<f32>(x, y, z)));
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+#ifdef SKINNED
+    var model = skin_model(vertex.joint_indices, vertex.joint_weights);
+#else // SKINNED
+    var model = mesh.model;
+#endif // SKINNED
+#ifdef INSTANCE_STORAGE_BUFFER
+    let instance_transform = instances[vertex.instance_index].transform;
+#else // INSTANCE_STORAGE_BUFFER
+    let instance_transform = mat4x4<f32>(
+        vertex.i_transform_0,
+        vertex.i_transform_1,
+        vertex.i_transform_2,
+        vertex.i_transform_3,
+    );
+#endif // INSTANCE_STORAGE_BUFFER
+    model = model * instance_transform;
+
+    out.clip_position = mesh_position_local_to_clip(model, vec4<f32>(vertex.position, 1.0));
+
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif // VERTEX_UVS
+
+#ifdef NORMAL_PREPASS
+    out.world_normal = normalize(instance_normal_matrix(model) * vertex.normal);
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_tangent_local_to_world(model, vertex.tangent);
+#endif // VERTEX_TANGENTS
+#endif // NORMAL_PREPASS
+
+    return out;
+}
@@ -0,0 +1,121 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+
+// NOTE: Bindings must come before functions that use them!
+#import bevy_pbr::mesh_functions
+
+#ifdef INSTANCE_STORAGE_BUFFER
+struct Instance {
+    transform: mat4x4<f32>,
+    color: vec4<f32>,
+};
+
+@group(3) @binding(0)
+var<storage, read> instances: array<Instance>;
+#endif
+
+struct Vertex {
+#ifdef VERTEX_POSITIONS
+    @location(0) position: vec3<f32>,
+#endif
+#ifdef VERTEX_NORMALS
+    @location(1) normal: vec3<f32>,
+#endif
+#ifdef VERTEX_UVS
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif
+#ifdef MESH_VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
+#ifdef SKINNED
+    @location(5) joint_indices: vec4<u32>,
+    @location(6) joint_weights: vec4<f32>,
+#endif
+#ifdef INSTANCE_STORAGE_BUFFER
+    @builtin(instance_index) instance_index: u32,
+#else
+    @location(8) i_transform_0: vec4<f32>,
+    @location(9) i_transform_1: vec4<f32>,
+    @location(10) i_transform_2: vec4<f32>,
+    @location(11) i_transform_3: vec4<f32>,
+    @location(12) i_color: vec4<f32>,
+#ifdef INSTANCE_PICKING
+    @builtin(instance_index) instance_index: u32,
+#endif
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    #import bevy_pbr::mesh_vertex_output
+#ifdef INSTANCE_PICKING
+    @location(9) @interpolate(flat) instance_index: u32,
+#endif
+};
+
+// Proportional to the inverse transpose of the upper 3x3 of `model`, which is all the normals
+// need since they are normalized afterwards.
+fn instance_normal_matrix(model: mat4x4<f32>) -> mat3x3<f32> {
+    let x = model[0].xyz;
+    let y = model[1].xyz;
+    let z = model[2].xyz;
+    return mat3x3<f32>(cross(y, z), cross(z, x), cross(x, y)) * sign(determinant(mat3x3<f32>(x, y, z)));
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+#ifdef SKINNED
+    var model = skin_model(vertex.joint_indices, vertex.joint_weights);
+#else
+    var model = mesh.model;
+#endif
+#ifdef INSTANCE_STORAGE_BUFFER
+    let instance_transform = instances[vertex.instance_index].transform;
+    let instance_color = instances[vertex.instance_index].color;
+#else
+    let instance_transform = mat4x4<f32>(
+        vertex.i_transform_0,
+        vertex.i_transform_1,
+        vertex.i_transform_2,
+        vertex.i_transform_3,
+    );
+    let instance_color = vertex.i_color;
+#endif
+    model = model * instance_transform;
+
+#ifdef VERTEX_NORMALS
+    out.world_normal = normalize(instance_normal_matrix(model) * vertex.normal);
+#endif
+
+#ifdef VERTEX_POSITIONS
+    out.world_position = mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0));
+    out.clip_position = mesh_position_world_to_clip(out.world_position);
+#endif
+
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif
+
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_tangent_local_to_world(model, vertex.tangent);
+#endif
+
+#ifdef VERTEX_COLORS
+#ifdef MESH_VERTEX_COLORS
+    out.color = vertex.color * instance_color;
+#else
+    out.color = instance_color;
+#endif
+#endif
+
+#ifdef INSTANCE_PICKING
+    out.instance_index = vertex.instance_index;
+#endif
+
+    return out;
+}
@@ -1,100 +1,754 @@
+use std::fmt;
 use std::marker::PhantomData;
 use std::hash::Hash;
+use std::ops::{Deref, DerefMut, Range};
 
 use bevy::{
     prelude::*,
+    asset::load_internal_asset,
     render::{
         extract_component::{ExtractComponentPlugin, ExtractComponent}, RenderApp, RenderStage,
+        main_graph, render_graph::RenderGraph,
         render_asset::{PrepareAssetLabel, RenderAssets},
         render_phase::{
             SetItemPipeline, PhaseItem, RenderCommand, TrackedRenderPass, RenderCommandResult, AddRenderCommand,
             DrawFunctions, RenderPhase
         },
-        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        mesh::{GpuBufferInfo, MeshVertexBufferLayout, MissingVertexAttributeError},
         render_resource::{
-            Buffer, BufferInitDescriptor, BufferUsages, SpecializedMeshPipeline, SpecializedMeshPipelineError,
-            RenderPipelineDescriptor, VertexBufferLayout, SpecializedMeshPipelines, PipelineCache
+            BindGroupLayout, Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, SpecializedMeshPipeline, SpecializedMeshPipelineError,
+            RenderPipelineDescriptor, VertexBufferLayout, CachedRenderPipelineId, PipelineCache, ShaderRef
         },
-        renderer::RenderDevice, view::{ExtractedView, VisibleEntities}
+        renderer::{RenderDevice, RenderQueue}, view::{ExtractedView, VisibleEntities, VisibilitySystems}
     },
+    utils::HashMap,
     ecs::{query::QueryItem, system::{lifetimeless::{SRes, Read}, SystemParamItem}},
     pbr::{
         ExtractedMaterials, RenderMaterials, extract_materials, prepare_materials, SetMeshViewBindGroup,
-        SetMaterialBindGroup, SetMeshBindGroup, MaterialPipeline, MaterialPipelineKey, MeshUniform, MeshPipelineKey
+        SetMaterialBindGroup, SetMeshBindGroup, MaterialPipeline, MaterialPipelineKey, MeshUniform, MeshPipelineKey,
+        RenderLightSystems, Shadow, PrepassPipelinePlugin, SimulationLightSystems
     },
-    core_pipeline::{core_3d::{Transparent3d, Opaque3d, AlphaMask3d}, tonemapping::Tonemapping}
+    core_pipeline::{
+        core_3d::{Transparent3d, Opaque3d, AlphaMask3d}, tonemapping::Tonemapping,
+        prepass::{Opaque3dPrepass, AlphaMask3dPrepass}
+    }
 };
 use bytemuck::{Pod, Zeroable};
 
-pub struct InstanceMaterialPlugin<M: Material, D: InstanceData>(PhantomData<M>, PhantomData<D>);
+mod animation_texture;
+mod batching;
+mod bvh;
+mod culling;
+mod gpu_culling;
+mod impostor;
+mod indirect;
+mod instance_id;
+mod lod;
+mod material2d;
+mod picking;
+mod prepass;
+mod raycast;
+mod shadow;
+mod standard_material;
+mod storage_buffer;
+mod streams;
+
+pub use bevy_instancing_derive::InstanceData;
+pub use animation_texture::*;
+pub use batching::*;
+pub use bvh::*;
+pub use culling::*;
+pub use gpu_culling::*;
+pub use impostor::*;
+pub use indirect::*;
+pub use instance_id::*;
+pub use lod::*;
+pub use material2d::*;
+pub use picking::*;
+pub use prepass::*;
+pub use raycast::*;
+pub use shadow::*;
+pub use standard_material::*;
+pub use storage_buffer::*;
+pub use streams::*;
+
+// Lets `#[derive(InstanceData)]` be used inside this crate.
+extern crate self as bevy_instancing;
+
+pub struct InstanceMaterialPlugin<M: Material, D: InstanceData> {
+    /// Queues the instances into the depth and normal prepasses of cameras with a `DepthPrepass`
+    /// or `NormalPrepass`, using [`InstanceData::prepass_vertex_shader`].
+    ///
+    /// The prepass phases themselves are set up by bevy's `PbrPlugin`.
+    pub prepass_enabled: bool,
+    /// Binds the instances as a storage buffer in the bind group after the mesh, instead of as a
+    /// vertex buffer, see [`push_instance_input`].
+    ///
+    /// Falls back to the vertex buffer where storage buffers are unsupported, e.g. on WebGL2.
+    /// Materials drawing the same `D` share its instance input, the first plugin added decides.
+    ///
+    /// The size of `D` must be a multiple of 16 bytes, matching its WGSL struct.
+    pub storage_buffer: bool,
+    /// Draws the instances into the picking pass of cameras with an [`InstancePicking`].
+    ///
+    /// The vertex shader of `M` must output the instance index under the `INSTANCE_PICKING` def,
+    /// see [`use_instance_picking`].
+    pub picking: bool,
+    pub _marker: PhantomData<(M, D)>,
+}
+
+impl<M: Material, D: InstanceData> Default for InstanceMaterialPlugin<M, D> {
+    fn default() -> Self {
+        InstanceMaterialPlugin {
+            prepass_enabled: false,
+            storage_buffer: false,
+            picking: false,
+            _marker: Default::default(),
+        }
+    }
+}
 
 impl<M: Material, D: InstanceData> Plugin for InstanceMaterialPlugin<M, D>
 where
     M::Data: PartialEq + Eq + Hash + Clone
 {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            ANIMATION_TEXTURE_SHADER_HANDLE,
+            "animation_texture.wgsl",
+            Shader::from_wgsl
+        );
+
+        if !app.is_plugin_added::<ImpostorPlugin>() {
+            app.add_plugin(ImpostorPlugin);
+        }
+        // Before the pipelines, which look up its layout.
+        if self.picking && !app.is_plugin_added::<InstancePickingPlugin>() {
+            app.add_plugin(InstancePickingPlugin);
+        }
+
+        add_instance_data::<D>(app);
+        add_instance_data_3d::<D>(app, self.storage_buffer);
         app.add_asset::<M>()
-            .add_plugin(ExtractComponentPlugin::<Handle<M>>::extract_visible())
-            .add_plugin(ExtractComponentPlugin::<InstanceDataVec<D>>::extract_visible());
-        
+            .add_plugin(ExtractComponentPlugin::<Handle<M>>::extract_visible());
+
         app.sub_app_mut(RenderApp)
             .init_resource::<ExtractedMaterials<M>>()
             .init_resource::<RenderMaterials<M>>()
             .add_system_to_stage(RenderStage::Extract, extract_materials::<M>)
+            .add_system_to_stage(RenderStage::Extract, extract_sorted_instances::<M, D>)
+            .add_system_to_stage(RenderStage::Extract, extract_instance_lods::<M, D>)
             .add_system_to_stage(
                 RenderStage::Prepare,
                 prepare_materials::<M>.after(PrepareAssetLabel::PreAssetPrepare),
             )
-            .add_system_to_stage(RenderStage::Prepare, prepare_instance_buffers::<D>)
-            
+
             .add_render_command::<Transparent3d, DrawInstancedMaterial<M>>()
             .add_render_command::<Opaque3d, DrawInstancedMaterial<M>>()
             .add_render_command::<AlphaMask3d, DrawInstancedMaterial<M>>()
             .init_resource::<InstanceMaterialPipeline<M, D>>()
-            .init_resource::<SpecializedMeshPipelines<InstanceMaterialPipeline<M, D>>>()
+            .init_resource::<SpecializedInstancePipelines<InstanceMaterialPipeline<M, D>>>()
             .add_system_to_stage(RenderStage::Queue, queue_instance_material_meshes::<M, D>);
+
+        if self.picking {
+            app.sub_app_mut(RenderApp)
+                .add_render_command::<InstancePicking3d, DrawInstancedPicking<M>>()
+                .add_system_to_stage(RenderStage::Queue, queue_instance_picking::<M, D>);
+        }
+
+        if self.prepass_enabled {
+            // Already added by `MaterialPlugin<M>` when `M` is also drawn without instancing.
+            if !app.is_plugin_added::<PrepassPipelinePlugin<M>>() {
+                app.sub_app_mut(RenderApp).init_resource::<MaterialPipeline<M>>();
+                app.add_plugin(PrepassPipelinePlugin::<M>::default());
+            }
+
+            app.sub_app_mut(RenderApp)
+                .add_render_command::<Opaque3dPrepass, DrawInstancedPrepass<M>>()
+                .add_render_command::<AlphaMask3dPrepass, DrawInstancedPrepass<M>>()
+                .init_resource::<InstancePrepassPipeline<M, D>>()
+                .init_resource::<SpecializedInstancePipelines<InstancePrepassPipeline<M, D>>>()
+                .add_system_to_stage(RenderStage::Queue, queue_instance_prepass_meshes::<M, D>);
+        }
     }
 }
 
-#[derive(Component, Deref)]
-pub struct InstanceDataVec<D: InstanceData>(Vec<D>);
+/// Extracts and uploads the [`InstanceDataVec`]s of `D`, shared by every plugin drawing `D`.
+fn add_instance_data<D: InstanceData>(app: &mut App) {
+    if app.is_plugin_added::<ExtractComponentPlugin<InstanceDataVec<D>>>() {
+        return;
+    }
+    app.add_plugin(ExtractComponentPlugin::<InstanceDataVec<D>>::default())
+        .add_system_to_stage(CoreStage::First, clear_dirty_instances::<D>);
+    app.sub_app_mut(RenderApp)
+        .init_resource::<InstanceBuffers<D>>()
+        .add_system_to_stage(RenderStage::Prepare, prepare_instance_buffers::<D>);
+}
+
+/// Culling, shadows, impostors and bounds of the instances of `D`, shared by every material
+/// drawing `D` so they only run once per frame. The first plugin decides on `storage_buffer`.
+fn add_instance_data_3d<D: InstanceData>(app: &mut App, storage_buffer: bool) {
+    if app.sub_app(RenderApp).world.contains_resource::<CulledInstances<D>>() {
+        return;
+    }
+    app.add_plugin(ExtractComponentPlugin::<ViewInstanceFilter<D>>::default())
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            calculate_instance_bounds::<D>
+                .after(VisibilitySystems::CalculateBounds)
+                .before(VisibilitySystems::CheckVisibility)
+                .before(SimulationLightSystems::CheckLightVisibility),
+        )
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            update_instance_bvhs::<D>.after(calculate_instance_bounds::<D>),
+        );
+
+    if storage_buffer || D::instance_bounds_wgsl().is_some() {
+        assert_storage_layout::<D>();
+    }
+
+    let render_app = app.sub_app_mut(RenderApp);
+    let storage_supported = storage_buffers_supported(render_app.world.resource::<RenderDevice>());
+    if storage_buffer && storage_supported {
+        // Before the pipelines, which look it up to pick the instance input.
+        render_app
+            .init_resource::<InstanceStorageLayout<D>>()
+            .add_system_to_stage(RenderStage::Queue, queue_instance_bind_groups::<D>.after(cull_instances::<D>));
+    }
+
+    render_app
+        .init_resource::<CulledInstances<D>>()
+        .init_resource::<IndirectBuffers<D>>()
+        .add_system_to_stage(RenderStage::Extract, extract_instance_mesh_radii::<D>)
+        .add_system_to_stage(RenderStage::Extract, extract_culled_instances::<D>)
+        .add_system_to_stage(RenderStage::Extract, extract_indirect_instances::<D>)
+        // Shadow views are only spawned during `RenderStage::Prepare`.
+        .add_system_to_stage(RenderStage::Queue, cull_instances::<D>)
+        .add_system_to_stage(RenderStage::Queue, prepare_indirect_buffers::<D>.after(cull_instances::<D>))
+
+        .add_render_command::<Shadow, DrawInstancedShadowMesh>()
+        .init_resource::<InstanceShadowPipeline<D>>()
+        .init_resource::<SpecializedInstancePipelines<InstanceShadowPipeline<D>>>()
+        .add_system_to_stage(
+            RenderStage::Queue,
+            queue_instance_shadows::<D>.after(RenderLightSystems::QueueShadows),
+        );
+
+    if !matches!(D::impostor_vertex_shader(), ShaderRef::Default) {
+        render_app
+            .add_render_command::<AlphaMask3d, DrawInstancedImpostor>()
+            .init_resource::<InstanceImpostorPipeline<D>>()
+            .init_resource::<SpecializedInstancePipelines<InstanceImpostorPipeline<D>>>()
+            .init_resource::<ImpostorBindGroups<D>>()
+            .add_system_to_stage(RenderStage::Queue, queue_instance_impostors::<D>);
+    }
+
+    // The pass reads and writes the instances as storage buffers.
+    if D::instance_bounds_wgsl().is_some() && storage_supported {
+        add_gpu_culling_shader::<D>(app);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<GpuCullingPipeline<D>>()
+            .init_resource::<GpuCullingDispatches<D>>()
+            .add_system_to_stage(RenderStage::Extract, extract_gpu_culled_instances::<D>)
+            .add_system_to_stage(
+                RenderStage::Queue,
+                queue_gpu_culling::<D>.after(prepare_indirect_buffers::<D>),
+            );
+
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node(GpuCullingNode::<D>::name(), GpuCullingNode::<D>::default());
+        render_graph
+            .add_node_edge(GpuCullingNode::<D>::name(), main_graph::node::CAMERA_DRIVER)
+            .unwrap();
+    }
+}
+
+/// Instances read from storage buffers are indexed with the stride of their WGSL struct, which
+/// is rounded up to its alignment, see [`push_instance_input`].
+fn assert_storage_layout<D: InstanceData>() {
+    assert!(
+        std::mem::size_of::<D>() % 16 == 0,
+        "{} is bound as a storage buffer, its size ({} bytes) must be a multiple of 16 bytes: \
+         add padding fields to match its WGSL struct",
+        D::buffer_label(),
+        std::mem::size_of::<D>(),
+    );
+}
+
+/// Per-entity instance data, uploaded to the gpu as one instance vertex buffer.
+///
+/// Mutate it through [`Mut<InstanceDataVec<D>>`] so bevy's change detection marks it as changed
+/// for the render world. The helpers below only mark the instances they touch as dirty, so only
+/// those get uploaded again. Mutating the inner [`Vec`] through [`DerefMut`] marks everything
+/// dirty.
+///
+/// Indices shift as instances are inserted and removed, see [`InstanceIds`] for stable handles.
+///
+/// [`DerefMut`]: std::ops::DerefMut
+#[derive(Component)]
+pub struct InstanceDataVec<D: InstanceData> {
+    data: Vec<D>,
+    dirty: Vec<Range<usize>>,
+    /// Counts the mutations, to tell whether copies like an [`InstanceBvh`] are up to date.
+    changes: u64,
+    /// `changes` when the dirty ranges were last cleared.
+    cleared_at: u64,
+}
+
+impl<D: InstanceData> InstanceDataVec<D> {
+    pub fn new() -> Self {
+        Self::from(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::from(Vec::with_capacity(capacity))
+    }
+
+    pub fn push(&mut self, data: D) {
+        self.data.push(data);
+        self.mark_dirty(self.data.len() - 1..self.data.len());
+    }
+
+    pub fn pop(&mut self) -> Option<D> {
+        self.changes += 1;
+        self.data.pop()
+    }
+
+    pub fn insert(&mut self, index: usize, data: D) {
+        self.data.insert(index, data);
+        self.mark_dirty(index..self.data.len());
+    }
+
+    pub fn remove(&mut self, index: usize) -> D {
+        let data = self.data.remove(index);
+        self.mark_dirty(index..self.data.len());
+        data
+    }
+
+    pub fn swap_remove(&mut self, index: usize) -> D {
+        let data = self.data.swap_remove(index);
+        self.mark_dirty(index..index + 1);
+        data
+    }
+
+    pub fn retain<F: FnMut(&D) -> bool>(&mut self, mut f: F) {
+        let mut index = 0;
+        let mut first_removed = None;
+        self.data.retain(|data| {
+            let keep = f(data);
+            if !keep && first_removed.is_none() {
+                first_removed = Some(index);
+            }
+            index += 1;
+            keep
+        });
+        if let Some(first_removed) = first_removed {
+            self.mark_dirty(first_removed..self.data.len());
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.changes += 1;
+        self.data.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.changes += 1;
+        self.data.clear();
+        self.dirty.clear();
+    }
+
+    /// Overwrites the instance at `index`.
+    pub fn set(&mut self, index: usize, data: D) {
+        self.data[index] = data;
+        self.mark_dirty(index..index + 1);
+    }
+
+    /// Mutable access to a single instance, marking only that instance as dirty.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut D> {
+        if index < self.data.len() {
+            self.mark_dirty(index..index + 1);
+        }
+        self.data.get_mut(index)
+    }
+
+    /// Mutable access to a range of instances, marking only that range as dirty.
+    pub fn range_mut(&mut self, range: Range<usize>) -> &mut [D] {
+        self.mark_dirty(range.clone());
+        &mut self.data[range]
+    }
+
+    /// Marks a range of instances to be uploaded again.
+    ///
+    /// Marked ranges are uploaded even when the instances were mutated through
+    /// [`Mut::bypass_change_detection`], but systems relying on change detection, e.g.
+    /// [`calculate_instance_bounds`], won't see the change.
+    pub fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.changes += 1;
+        // Instances touched one after the other, e.g. by `get_mut` in a loop, share a range.
+        match self.dirty.last_mut() {
+            Some(last) if range.start <= last.end && last.start <= range.end => {
+                last.start = last.start.min(range.start);
+                last.end = last.end.max(range.end);
+            }
+            _ => self.dirty.push(range),
+        }
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        self.changes += 1;
+        self.dirty.clear();
+        self.dirty.push(0..usize::MAX);
+    }
+
+    /// The dirty ranges since the last extraction, sorted, merged and clamped to the current length.
+    pub fn dirty_ranges(&self) -> Vec<Range<usize>> {
+        let len = self.data.len();
+        let mut ranges: Vec<Range<usize>> = self.dirty.iter()
+            .map(|range| range.start.min(len)..range.end.min(len))
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Mutations so far, pass them to [`InstanceDataVec::changed_ranges_since`] later on.
+    pub(crate) fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// The ranges changed since `changes`, `None` when nothing changed. Everything is returned
+    /// when the dirty ranges were cleared in between.
+    pub(crate) fn changed_ranges_since(&self, changes: u64) -> Option<Vec<Range<usize>>> {
+        if changes == self.changes {
+            None
+        } else if changes >= self.cleared_at {
+            Some(self.dirty_ranges())
+        } else {
+            Some(vec![0..self.data.len()])
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<D> {
+        self.data
+    }
+}
+
+impl<D: InstanceData> Deref for InstanceDataVec<D> {
+    type Target = Vec<D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl<D: InstanceData> DerefMut for InstanceDataVec<D> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.mark_all_dirty();
+        &mut self.data
+    }
+}
+
+impl<D: InstanceData> Default for InstanceDataVec<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: InstanceData> From<Vec<D>> for InstanceDataVec<D> {
+    fn from(data: Vec<D>) -> Self {
+        InstanceDataVec {
+            data,
+            dirty: Vec::new(),
+            changes: 0,
+            cleared_at: 0,
+        }
+    }
+}
+
+impl<D: InstanceData> FromIterator<D> for InstanceDataVec<D> {
+    fn from_iter<I: IntoIterator<Item = D>>(iter: I) -> Self {
+        Self::from(Vec::from_iter(iter))
+    }
+}
+
+impl<D: InstanceData> Extend<D> for InstanceDataVec<D> {
+    fn extend<I: IntoIterator<Item = D>>(&mut self, iter: I) {
+        let start = self.data.len();
+        self.data.extend(iter);
+        self.mark_dirty(start..self.data.len());
+    }
+}
 
 impl<D: InstanceData> ExtractComponent for InstanceDataVec<D> {
-    type Query = &'static InstanceDataVec<D>;
+    type Query = (&'static InstanceDataVec<D>, ChangeTrackers<InstanceDataVec<D>>);
     type Filter = ();
-    type Out = Self;
+    type Out = ExtractedInstanceData<D>;
 
-    fn extract_component(item: QueryItem<'_, Self::Query>) -> Option<Self> {
-        Some(InstanceDataVec::<D>(item.0.clone()))
+    fn extract_component((item, tracker): QueryItem<'_, Self::Query>) -> Option<Self::Out> {
+        // Ranges may also be marked without triggering change detection.
+        let ranges = if tracker.is_added() {
+            vec![0..item.len()]
+        } else {
+            item.dirty_ranges()
+        };
+        let data = ranges.iter()
+            .flat_map(|range| item.data[range.clone()].iter().copied())
+            .collect();
+
+        Some(ExtractedInstanceData {
+            len: item.len(),
+            ranges,
+            data,
+        })
+    }
+}
+
+/// Dirty ranges are consumed by the extraction at the end of the previous frame.
+pub fn clear_dirty_instances<D: InstanceData>(mut query: Query<&mut InstanceDataVec<D>>) {
+    for mut instance_data in &mut query {
+        if !instance_data.dirty.is_empty() {
+            let instance_data = instance_data.bypass_change_detection();
+            instance_data.dirty.clear();
+            instance_data.cleared_at = instance_data.changes;
+        }
     }
 }
 
+/// Render world copy of the dirty parts of an [`InstanceDataVec`].
+///
+/// `data` holds the instances of every range in `ranges`, back to back. Everything else is
+/// already in the persistent [`InstanceBuffer`] of the entity.
+#[derive(Component)]
+pub struct ExtractedInstanceData<D: InstanceData> {
+    len: usize,
+    ranges: Vec<Range<usize>>,
+    data: Vec<D>,
+}
+
+/// Per-instance data bound as an instance rate vertex buffer.
+///
+/// Use `#[derive(InstanceData)]` to generate [`InstanceData::buffer_layout`] from the fields of
+/// a `#[repr(C)]` struct instead of writing the layout by hand.
 pub trait InstanceData : Clone + Copy + Send + Sync + Pod + Zeroable {
     fn buffer_layout() -> VertexBufferLayout;
     fn buffer_label() -> &'static str { std::any::type_name::<Self>() }
+
+    /// Vertex shader rendering the instances into shadow maps, with bevy's shadow pipeline
+    /// bindings. Instances cast no shadows when this is [`ShaderRef::Default`].
+    fn shadow_vertex_shader() -> ShaderRef { ShaderRef::Default }
+
+    /// Vertex shader rendering the instances into the depth and normal prepasses, with the inputs
+    /// and outputs of bevy's `prepass.wgsl`. Instances are left out of the prepasses when this is
+    /// [`ShaderRef::Default`].
+    fn prepass_vertex_shader() -> ShaderRef { ShaderRef::Default }
+
+    /// Vertex shader drawing the instances as the billboards of an [`InstanceImpostor`], placing
+    /// them with `impostor_vertex` from `bevy_instancing::impostor`. Impostors aren't drawn when
+    /// this is [`ShaderRef::Default`].
+    fn impostor_vertex_shader() -> ShaderRef { ShaderRef::Default }
+
+    /// Center of the instance, in the local space of its entity.
+    ///
+    /// Returning `Some` enables per-instance frustum culling, see [`cull_instances`], bounds
+    /// enclosing every instance, see [`calculate_instance_bounds`], and [`SortInstances`]. It has
+    /// to return `Some` for every instance or for none of them.
+    fn position(&self) -> Option<Vec3> { None }
+
+    /// Radius of a sphere around [`InstanceData::position`] enclosing the instance, given the
+    /// radius of a sphere around the origin enclosing its mesh.
+    fn radius(&self, mesh_radius: f32) -> f32 { mesh_radius }
+
+    /// Transform of the instance in the local space of its entity, used by
+    /// [`raycast_instances`] to test the triangles of the mesh of the instance.
+    fn transform(&self) -> Option<Mat4> { None }
+
+    /// The instance moved by `transform`, used by [`InstanceBatchPlugin`] to move instances into
+    /// world space. It has to return `Some` for `D` to be batched.
+    fn transformed(&self, _transform: Mat4) -> Option<Self> { None }
+
+    /// WGSL declaring a `struct Instance` with the layout of `Self`, and a
+    /// `fn instance_bounds(instance: Instance, mesh_radius: f32) -> vec4<f32>` returning the
+    /// center of the instance in the local space of its entity in `xyz` and its radius in `w`.
+    ///
+    /// Returning `Some` makes [`GpuCulling`] available for `Self`.
+    fn instance_bounds_wgsl() -> Option<&'static str> { None }
+
+    /// Row of the texture baked by [`bake_animation_texture`] posing the instance, for skinned
+    /// meshes.
+    ///
+    /// bevy binds one set of joint matrices per entity, so every instance would share one pose.
+    /// Returning `Some` instead drops bevy's skinning for the instanced pipelines, see
+    /// [`use_animation_texture`]: the material binds the baked texture, e.g. with
+    /// `#[texture(N, sample_type = "float", filterable = false)]`, and its vertex shader skins the
+    /// mesh with `animation_skin_model` from `bevy_instancing::animation_texture` under the
+    /// `ANIMATION_TEXTURE` def. The entities use the skinned mesh without a `SkinnedMesh`.
+    ///
+    /// Animated skinned instances cast no shadows and are left out of the prepasses, whose
+    /// shaders don't support the animation texture.
+    ///
+    /// It has to return `Some` for every instance or for none of them.
+    fn animation_frame(&self) -> Option<u32> { None }
 }
 
-#[derive(Component)]
+/// Instance buffers are also bound as storage buffers where the device supports them, e.g. by
+/// the [`GpuCulling`] pass or [`InstanceMaterialPlugin::storage_buffer`].
+fn instance_buffer_usages(render_device: &RenderDevice) -> BufferUsages {
+    let usages = BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::COPY_SRC;
+    if storage_buffers_supported(render_device) {
+        usages | BufferUsages::STORAGE
+    } else {
+        usages
+    }
+}
+
+#[derive(Component, Clone)]
 pub struct InstanceBuffer {
     buffer: Buffer,
     length: usize,
+    capacity: usize,
 }
 
-pub fn prepare_instance_buffers<D: InstanceData>(
-    mut commands: Commands,
-    query: Query<(Entity, &InstanceDataVec<D>)>,
-    render_device: Res<RenderDevice>,
-) {
-    for (entity, instance_data) in &query {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+impl InstanceBuffer {
+    fn with_capacity<D: InstanceData>(render_device: &RenderDevice, capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        let buffer = render_device.create_buffer(&BufferDescriptor {
             label: Some(D::buffer_label()),
-            contents: bytemuck::cast_slice(instance_data.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            size: (capacity * std::mem::size_of::<D>()) as u64,
+            usage: instance_buffer_usages(render_device),
+            mapped_at_creation: false,
         });
-        commands.entity(entity).insert(InstanceBuffer {
+        InstanceBuffer {
             buffer,
-            length: instance_data.len(),
+            length: 0,
+            capacity,
+        }
+    }
+
+    /// Grows the buffer to fit `len` instances, keeping the instances already uploaded.
+    fn reserve<D: InstanceData>(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue, len: usize) {
+        if len <= self.capacity {
+            return;
+        }
+        let grown = InstanceBuffer::with_capacity::<D>(render_device, len);
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("instance_buffer_grow"),
         });
+        encoder.copy_buffer_to_buffer(
+            &self.buffer, 0,
+            &grown.buffer, 0,
+            (self.length * std::mem::size_of::<D>()) as u64,
+        );
+        // Submit right away, the dirty ranges written afterwards must land on top of the copy.
+        render_queue.submit([encoder.finish()]);
+        self.buffer = grown.buffer;
+        self.capacity = grown.capacity;
+    }
+
+    /// Replaces the whole content of the buffer with `data`.
+    fn write_all<D: InstanceData>(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue, data: &[D]) {
+        if data.len() > self.capacity {
+            *self = InstanceBuffer::with_capacity::<D>(render_device, data.len());
+        }
+        if !data.is_empty() {
+            render_queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(data));
+        }
+        self.length = data.len();
+    }
+
+    fn write<D: InstanceData>(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        extracted: &ExtractedInstanceData<D>,
+    ) {
+        self.reserve::<D>(render_device, render_queue, extracted.len);
+
+        let mut data = extracted.data.as_slice();
+        for range in &extracted.ranges {
+            let (dirty, rest) = data.split_at(range.len());
+            render_queue.write_buffer(
+                &self.buffer,
+                (range.start * std::mem::size_of::<D>()) as u64,
+                bytemuck::cast_slice(dirty),
+            );
+            data = rest;
+        }
+        self.length = extracted.len;
+    }
+}
+
+/// Subsets of the instances of an entity drawn in specific views, keyed by the view entity, see
+/// [`cull_instances`].
+///
+/// Views without an entry draw the whole [`InstanceBuffer`].
+#[derive(Component, Clone, Default)]
+pub struct ViewInstanceBuffers(pub HashMap<Entity, InstanceBuffer>);
+
+/// Instance buffers kept alive across frames, keyed by the entity owning the instance data.
+///
+/// Render world entities are cleared every frame, so the buffers live here and a clone of
+/// each one is inserted into its entity during [`RenderStage::Prepare`].
+#[derive(Resource)]
+pub struct InstanceBuffers<D: InstanceData> {
+    buffers: HashMap<Entity, InstanceBuffer>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> Default for InstanceBuffers<D> {
+    fn default() -> Self {
+        InstanceBuffers {
+            buffers: HashMap::default(),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<D: InstanceData> InstanceBuffers<D> {
+    /// Uploads the instances extracted this frame.
+    fn prepare(
+        &mut self,
+        query: &Query<(Entity, &ExtractedInstanceData<D>)>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) {
+        // Instance data which wasn't extracted this frame was removed or despawned.
+        self.buffers.retain(|entity, _| query.contains(*entity));
+
+        for (entity, extracted) in query {
+            let instance_buffer = self.buffers
+                .entry(entity)
+                .or_insert_with(|| InstanceBuffer::with_capacity::<D>(render_device, extracted.len));
+            instance_buffer.write(render_device, render_queue, extracted);
+        }
+    }
+}
+
+pub fn prepare_instance_buffers<D: InstanceData>(
+    mut commands: Commands,
+    query: Query<(Entity, &ExtractedInstanceData<D>)>,
+    mut instance_buffers: ResMut<InstanceBuffers<D>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    instance_buffers.prepare(&query, &render_device, &render_queue);
+    for (entity, instance_buffer) in &instance_buffers.buffers {
+        commands.entity(*entity).insert(instance_buffer.clone());
     }
 }
 
@@ -103,31 +757,80 @@ type DrawInstancedMaterial<M> = (
     SetMeshViewBindGroup<0>,
     SetMaterialBindGroup<M, 1>,
     SetMeshBindGroup<2>,
-    DrawMeshInstanced,
+    SetInstanceBindGroup<3>,
+    DrawMeshInstanced<true>,
 );
 
-pub struct DrawMeshInstanced;
+/// Draws the instances of the item.
+///
+/// `STORAGE_BUFFER` is for pipelines built with [`push_instance_input`], which read the instances
+/// of entities with [`InstanceBindGroups`] from a storage buffer, so they take no vertex buffer
+/// slot. Pipelines built with [`push_instance_buffer_layout`], like the 2d ones, always get them
+/// as a vertex buffer.
+pub struct DrawMeshInstanced<const STORAGE_BUFFER: bool>;
 
-impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
+impl<P: PhaseItem, const STORAGE_BUFFER: bool> RenderCommand<P> for DrawMeshInstanced<STORAGE_BUFFER> {
     type Param = SRes<RenderAssets<Mesh>>;
-    type ViewWorldQuery = ();
-    type ItemWorldQuery = (Read<Handle<Mesh>>, Read<InstanceBuffer>);
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = (
+        Read<Handle<Mesh>>,
+        Option<Read<InstanceBuffer>>,
+        Option<Read<ViewInstanceBuffers>>,
+        Option<Read<IndirectBuffer>>,
+        Option<Read<InstanceStreams>>,
+        Option<Read<InstanceBindGroups>>,
+    );
 
     #[inline]
     fn render<'w>(
         _item: &P,
-        _view: (),
-        (mesh_handle, instance_buffer): (&'w Handle<Mesh>, &'w InstanceBuffer),
+        view: Entity,
+        (
+            mesh_handle,
+            instance_buffer,
+            view_instance_buffers,
+            indirect_buffer,
+            instance_streams,
+            instance_bind_groups,
+        ): (
+            &'w Handle<Mesh>,
+            Option<&'w InstanceBuffer>,
+            Option<&'w ViewInstanceBuffers>,
+            Option<&'w IndirectBuffer>,
+            Option<&'w InstanceStreams>,
+            Option<&'w InstanceBindGroups>,
+        ),
         meshes: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        // `InstanceLod` level entities only have view buffers.
+        let instance_buffer = match view_instance_buffers
+            .and_then(|view_instance_buffers| view_instance_buffers.0.get(&view))
+            .or(instance_buffer)
+        {
+            Some(instance_buffer) => instance_buffer,
+            None => return RenderCommandResult::Failure,
+        };
+
         let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
             Some(gpu_mesh) => gpu_mesh,
             None => return RenderCommandResult::Failure,
         };
 
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+        // Instances bound as a storage buffer take no vertex buffer slot.
+        let mut slot = 1;
+        if !STORAGE_BUFFER || instance_bind_groups.is_none() {
+            pass.set_vertex_buffer(slot, instance_buffer.buffer.slice(..));
+            slot += 1;
+        }
+        let mut instance_count = instance_buffer.length as u32;
+        for stream in instance_streams.iter().flat_map(|instance_streams| &instance_streams.0) {
+            pass.set_vertex_buffer(slot, stream.buffer.buffer.slice(..));
+            slot += 1;
+            // Instances past the end of a stream would read out of its bounds.
+            instance_count = instance_count.min(stream.buffer.length as u32);
+        }
 
         match &gpu_mesh.buffer_info {
             GpuBufferInfo::Indexed {
@@ -136,10 +839,20 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
                 count,
             } => {
                 pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
+                match indirect_buffer {
+                    Some(indirect_buffer) if indirect_buffer.indexed => {
+                        pass.draw_indexed_indirect(&indirect_buffer.buffer, indirect_buffer.offset(view));
+                    }
+                    _ => pass.draw_indexed(0..*count, 0, 0..instance_count),
+                }
             }
             GpuBufferInfo::NonIndexed { vertex_count } => {
-                pass.draw(0..*vertex_count, 0..instance_buffer.length as u32);
+                match indirect_buffer {
+                    Some(indirect_buffer) if !indirect_buffer.indexed => {
+                        pass.draw_indirect(&indirect_buffer.buffer, indirect_buffer.offset(view));
+                    }
+                    _ => pass.draw(0..*vertex_count, 0..instance_count),
+                }
             }
         }
         RenderCommandResult::Success
@@ -151,12 +864,16 @@ fn queue_instance_material_meshes<M: Material, D: InstanceData>(
     alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_draw_functions: Res<DrawFunctions<Transparent3d>>,
     instance_material_pipeline: Res<InstanceMaterialPipeline<M, D>>,
-    mut pipelines: ResMut<SpecializedMeshPipelines<InstanceMaterialPipeline<M, D>>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstanceMaterialPipeline<M, D>>>,
     pipeline_cache: Res<PipelineCache>,
     msaa: Res<Msaa>,
     render_meshes: Res<RenderAssets<Mesh>>,
     render_materials: Res<RenderMaterials<M>>,
-    instance_material_meshes: Query<(&Handle<M>, &Handle<Mesh>, &MeshUniform), With<InstanceDataVec<D>>>,
+    instance_material_meshes: Query<
+        (&Handle<M>, &Handle<Mesh>, &MeshUniform, Option<&InstanceStreams>, Option<&ExtractedInstanceLod>),
+        With<ExtractedInstanceData<D>>,
+    >,
+    lod_meshes: Query<&Handle<Mesh>, Without<ImpostorLevel>>,
     mut views: Query<(
         &ExtractedView,
         &VisibleEntities,
@@ -198,69 +915,76 @@ fn queue_instance_material_meshes<M: Material, D: InstanceData>(
             let (
                 material_handle,
                 mesh_handle,
-                mesh_uniform
+                mesh_uniform,
+                instance_streams,
+                lod
             ) = match instance_material_meshes.get(*visible_entity) {
                 Ok(mesh) => mesh,
-                Err(_) => return,
+                Err(_) => continue,
             };
             let material = match render_materials.get(material_handle) {
                 Some(material) => material,
-                None => return,
-            };
-            let mesh = match render_meshes.get(mesh_handle) {
-                Some(mesh) => mesh,
-                None => return,
+                None => continue,
             };
+            let lod_levels = lod.iter()
+                .flat_map(|lod| &lod.levels)
+                .filter_map(|(level_entity, _)| Some((*level_entity, lod_meshes.get(*level_entity).ok()?)));
+            for (item_entity, mesh_handle) in std::iter::once((*visible_entity, mesh_handle)).chain(lod_levels) {
+                let mesh = match render_meshes.get(mesh_handle) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
 
-            let mut mesh_key =
-                MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
-            let alpha_mode = material.properties.alpha_mode;
-            if let AlphaMode::Blend = alpha_mode {
-                mesh_key |= MeshPipelineKey::TRANSPARENT_MAIN_PASS;
-            }
-
-            let pipeline_id = pipelines.specialize(
-                &pipeline_cache,
-                &instance_material_pipeline,
-                MaterialPipelineKey {
-                    mesh_key,
-                    bind_group_data: material.key.clone(),
-                },
-                &mesh.layout,
-            );
-            let pipeline_id = match pipeline_id {
-                Ok(id) => id,
-                Err(err) => {
-                    error!("{}", err);
-                    continue;
+                let mut mesh_key =
+                    MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
+                let alpha_mode = material.properties.alpha_mode;
+                if let AlphaMode::Blend = alpha_mode {
+                    mesh_key |= MeshPipelineKey::TRANSPARENT_MAIN_PASS;
                 }
-            };
 
-            let distance = rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
-            match alpha_mode {
-                AlphaMode::Opaque => {
-                    opaque_phase.add(Opaque3d {
-                        entity: *visible_entity,
-                        draw_function: draw_opaque_pbr,
-                        pipeline: pipeline_id,
-                        distance,
-                    });
-                }
-                AlphaMode::Mask(_) => {
-                    alpha_mask_phase.add(AlphaMask3d {
-                        entity: *visible_entity,
-                        draw_function: draw_alpha_mask_pbr,
-                        pipeline: pipeline_id,
-                        distance,
-                    });
-                }
-                AlphaMode::Blend => {
-                    transparent_phase.add(Transparent3d {
-                        entity: *visible_entity,
-                        draw_function: draw_transparent_pbr,
-                        pipeline: pipeline_id,
-                        distance,
-                    });
+                let pipeline_id = pipelines.specialize(
+                    &pipeline_cache,
+                    &instance_material_pipeline,
+                    InstancePipelineKey::new(
+                        MaterialPipelineKey {
+                            mesh_key,
+                            bind_group_data: material.key.clone(),
+                        },
+                        instance_streams,
+                    ),
+                    &mesh.layout,
+                );
+                let pipeline_id = match pipeline_id {
+                    Some(id) => id,
+                    None => continue,
+                };
+
+                let distance = rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
+                match alpha_mode {
+                    AlphaMode::Opaque => {
+                        opaque_phase.add(Opaque3d {
+                            entity: item_entity,
+                            draw_function: draw_opaque_pbr,
+                            pipeline: pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Mask(_) => {
+                        alpha_mask_phase.add(AlphaMask3d {
+                            entity: item_entity,
+                            draw_function: draw_alpha_mask_pbr,
+                            pipeline: pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Blend => {
+                        transparent_phase.add(Transparent3d {
+                            entity: item_entity,
+                            draw_function: draw_transparent_pbr,
+                            pipeline: pipeline_id,
+                            distance,
+                        });
+                    }
                 }
             }
         }
@@ -270,6 +994,8 @@ fn queue_instance_material_meshes<M: Material, D: InstanceData>(
 #[derive(Resource, Clone)]
 pub struct InstanceMaterialPipeline<M: Material, D: InstanceData> {
     pub material_pipeline: MaterialPipeline<M>,
+    pub instance_storage_layout: Option<BindGroupLayout>,
+    pub picking_layout: Option<BindGroupLayout>,
     _data: PhantomData<D>,
 }
 
@@ -277,24 +1003,284 @@ impl<M: Material, D: InstanceData> FromWorld for InstanceMaterialPipeline<M, D>
     fn from_world(world: &mut World) -> Self {
         InstanceMaterialPipeline {
             material_pipeline: MaterialPipeline::from_world(world),
+            instance_storage_layout: world.get_resource::<InstanceStorageLayout<D>>()
+                .map(|storage_layout| storage_layout.layout.clone()),
+            picking_layout: world.get_resource::<InstancePickingLayout>()
+                .map(|picking_layout| picking_layout.layout.clone()),
             _data: Default::default()
         }
     }
 }
 
-impl<M: Material, D: InstanceData> SpecializedMeshPipeline for InstanceMaterialPipeline<M, D>
+impl<M: Material, D: InstanceData> SpecializedInstancePipeline for InstanceMaterialPipeline<M, D>
 where
     M::Data: PartialEq + Eq + Hash + Clone,
 {
-    type Key = MaterialPipelineKey<M>;
+    type Key = InstancePipelineKey<MaterialPipelineKey<M>>;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
-    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.material_pipeline.specialize(key, layout)?;
-        descriptor.vertex.buffers.push(D::buffer_layout());
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError> {
+        let mut descriptor = self.material_pipeline.specialize(key.key, layout)?;
+        let mesh_pipeline = &self.material_pipeline.mesh_pipeline;
+        use_animation_texture::<D>(&mut descriptor, &mesh_pipeline.mesh_layout, &mesh_pipeline.skinned_mesh_layout);
+        push_instance_input::<D>(&mut descriptor, self.instance_storage_layout.as_ref())?;
+        push_instance_stream_layouts(&mut descriptor, &key.stream_layouts)?;
+        if let (true, Some(picking_layout)) = (key.picking, &self.picking_layout) {
+            use_instance_picking(&mut descriptor, picking_layout);
+        }
         Ok(descriptor)
     }
 }
+
+/// Error specializing one of the instanced pipelines.
+#[derive(Debug)]
+pub enum InstancePipelineError {
+    /// The wrapped bevy pipeline failed, e.g. the mesh lacks an attribute it needs.
+    Mesh(SpecializedMeshPipelineError),
+    /// The vertex buffer `label` reads an attribute at a shader location already used by the mesh
+    /// or another vertex buffer, as the bundled shaders read the instances at fixed locations.
+    LocationCollision { label: String, location: u32 },
+}
+
+impl fmt::Display for InstancePipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstancePipelineError::Mesh(err) => err.fmt(f),
+            InstancePipelineError::LocationCollision { label, location } => write!(
+                f,
+                "{}: shader location {} is already used by the mesh or another vertex buffer",
+                label, location
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InstancePipelineError {}
+
+impl From<SpecializedMeshPipelineError> for InstancePipelineError {
+    fn from(err: SpecializedMeshPipelineError) -> Self {
+        InstancePipelineError::Mesh(err)
+    }
+}
+
+impl From<MissingVertexAttributeError> for InstancePipelineError {
+    fn from(err: MissingVertexAttributeError) -> Self {
+        InstancePipelineError::Mesh(err.into())
+    }
+}
+
+/// [`SpecializedMeshPipeline`] of the instanced pipelines, whose error can tell what the
+/// instances themselves got wrong.
+pub trait SpecializedInstancePipeline {
+    type Key: Clone + Hash + PartialEq + Eq;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError>;
+}
+
+/// The pipelines specialized from `P`, by mesh layout and key, as bevy's
+/// [`SpecializedMeshPipelines`](bevy::render::render_resource::SpecializedMeshPipelines).
+/// Failures are kept too, so each is logged once rather than every frame.
+#[derive(Resource)]
+pub struct SpecializedInstancePipelines<P: SpecializedInstancePipeline> {
+    pipelines: HashMap<(MeshVertexBufferLayout, P::Key), Option<CachedRenderPipelineId>>,
+}
+
+impl<P: SpecializedInstancePipeline> Default for SpecializedInstancePipelines<P> {
+    fn default() -> Self {
+        SpecializedInstancePipelines {
+            pipelines: Default::default(),
+        }
+    }
+}
+
+impl<P: SpecializedInstancePipeline> SpecializedInstancePipelines<P> {
+    /// The pipeline of `key` and `layout`, queued to `cache` the first time. `None` when
+    /// `pipeline` failed to specialize, which is logged the first time.
+    pub fn specialize(
+        &mut self,
+        cache: &PipelineCache,
+        pipeline: &P,
+        key: P::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Option<CachedRenderPipelineId> {
+        *self.pipelines.entry((layout.clone(), key.clone()))
+            .or_insert_with(|| match pipeline.specialize(key, layout) {
+                Ok(descriptor) => Some(cache.queue_render_pipeline(descriptor)),
+                Err(err) => {
+                    error!("{}", err);
+                    None
+                }
+            })
+    }
+}
+
+/// Appends the instance buffer layout of `D` to the vertex buffers of `descriptor`.
+///
+/// Fails if an instance attribute collides with a shader location already used by the mesh or
+/// another vertex buffer, as the bundled shaders read the instances at fixed locations.
+pub fn push_instance_buffer_layout<D: InstanceData>(
+    descriptor: &mut RenderPipelineDescriptor,
+) -> Result<(), InstancePipelineError> {
+    push_vertex_buffer_layout(descriptor, D::buffer_layout(), D::buffer_label())
+}
+
+/// Appends `buffer_layout` to the vertex buffers of `descriptor`, failing if one of its shader
+/// locations is already used.
+fn push_vertex_buffer_layout(
+    descriptor: &mut RenderPipelineDescriptor,
+    buffer_layout: VertexBufferLayout,
+    label: &str,
+) -> Result<(), InstancePipelineError> {
+    if let Some(location) = colliding_location(&descriptor.vertex.buffers, &buffer_layout) {
+        return Err(InstancePipelineError::LocationCollision {
+            label: label.to_string(),
+            location,
+        });
+    }
+    descriptor.vertex.buffers.push(buffer_layout);
+    Ok(())
+}
+
+/// First shader location of `buffer_layout` already used by one of `buffers`.
+fn colliding_location(buffers: &[VertexBufferLayout], buffer_layout: &VertexBufferLayout) -> Option<u32> {
+    buffer_layout.attributes.iter()
+        .map(|attribute| attribute.shader_location)
+        .find(|location| buffers.iter()
+            .flat_map(|buffer| &buffer.attributes)
+            .any(|attribute| attribute.shader_location == *location))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::{VertexAttribute, VertexFormat, VertexStepMode};
+
+    use super::*;
+
+    fn instance_vec(len: usize) -> InstanceDataVec<StandardInstanceData> {
+        InstanceDataVec::from(vec![StandardInstanceData::default(); len])
+    }
+
+    #[test]
+    fn dirty_ranges_are_sorted_and_merged() {
+        let mut instances = instance_vec(10);
+        instances.set(7, StandardInstanceData::default());
+        instances.set(2, StandardInstanceData::default());
+        instances.range_mut(5..7);
+        instances.set(3, StandardInstanceData::default());
+        assert_eq!(instances.dirty_ranges(), vec![2..4, 5..8]);
+    }
+
+    #[test]
+    fn dirty_ranges_are_clamped_to_the_length() {
+        let mut instances = instance_vec(10);
+        instances.range_mut(6..10);
+        instances.truncate(8);
+        assert_eq!(instances.dirty_ranges(), vec![6..8]);
+
+        instances.mark_all_dirty();
+        assert_eq!(instances.dirty_ranges(), vec![0..8]);
+
+        instances.truncate(0);
+        assert!(instances.dirty_ranges().is_empty());
+    }
+
+    #[test]
+    fn helpers_mark_the_instances_they_move() {
+        let mut instances = instance_vec(5);
+        instances.remove(1);
+        assert_eq!(instances.dirty_ranges(), vec![1..4]);
+
+        let mut instances = instance_vec(5);
+        instances.swap_remove(0);
+        assert_eq!(instances.dirty_ranges(), vec![0..1]);
+
+        let mut instances = instance_vec(5);
+        instances.push(StandardInstanceData::default());
+        instances.extend([StandardInstanceData::default(); 2]);
+        assert_eq!(instances.dirty_ranges(), vec![5..8]);
+
+        let mut instances = instance_vec(5);
+        let mut index = 0;
+        instances.retain(|_| {
+            index += 1;
+            index != 3
+        });
+        assert_eq!(instances.dirty_ranges(), vec![2..4]);
+
+        let mut instances = instance_vec(5);
+        instances.get_mut(5);
+        assert!(instances.dirty_ranges().is_empty());
+    }
+
+    #[test]
+    fn adjacent_marks_share_a_range() {
+        let mut instances = instance_vec(1000);
+        for index in 0..1000 {
+            instances.get_mut(index);
+        }
+        instances.get_mut(10);
+        assert_eq!(instances.dirty, vec![0..1000]);
+
+        instances.get_mut(1001);
+        instances.mark_dirty(2000..2000);
+        assert_eq!(instances.dirty, vec![0..1000]);
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Pod, Zeroable, InstanceData)]
+    struct LayoutInstance {
+        #[instance(location = 3)]
+        transform: Mat4,
+        color: Vec4,
+        #[instance(skip)]
+        _padding: [f32; 3],
+        scale: f32,
+    }
+
+    #[test]
+    fn derived_layout_follows_the_fields() {
+        let layout = LayoutInstance::buffer_layout();
+        assert_eq!(layout.array_stride, 96);
+        assert_eq!(layout.step_mode, VertexStepMode::Instance);
+
+        let attributes = layout.attributes.iter()
+            .map(|attribute| (attribute.format, attribute.offset, attribute.shader_location))
+            .collect::<Vec<_>>();
+        assert_eq!(attributes, vec![
+            (VertexFormat::Float32x4, 0, 3),
+            (VertexFormat::Float32x4, 16, 4),
+            (VertexFormat::Float32x4, 32, 5),
+            (VertexFormat::Float32x4, 48, 6),
+            (VertexFormat::Float32x4, 64, 7),
+            (VertexFormat::Float32, 92, 8),
+        ]);
+    }
+
+    #[test]
+    fn instance_locations_collide_with_any_used_location() {
+        let buffer_layout = |locations: &[u32]| VertexBufferLayout {
+            array_stride: 16,
+            step_mode: VertexStepMode::Instance,
+            attributes: locations.iter()
+                .map(|location| VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: 0,
+                    shader_location: *location,
+                })
+                .collect(),
+        };
+        let buffers = [buffer_layout(&[0, 1, 2]), LayoutInstance::buffer_layout()];
+
+        assert_eq!(colliding_location(&buffers, &buffer_layout(&[9, 10])), None);
+        assert_eq!(colliding_location(&buffers, &buffer_layout(&[9, 2])), Some(2));
+        assert_eq!(colliding_location(&buffers, &buffer_layout(&[10, 8, 7])), Some(8));
+        assert_eq!(colliding_location(&[], &buffer_layout(&[0])), None);
+    }
+}
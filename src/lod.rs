@@ -0,0 +1,182 @@
+use bevy::{
+    prelude::*,
+    math::Mat3A,
+    render::{render_resource::ShaderRef, Extract},
+    pbr::{MeshFlags, MeshUniform, NotShadowReceiver},
+};
+
+use crate::{GpuCulling, ImpostorLevel, InstanceData, InstanceDataVec, InstanceImpostor};
+
+/// Lower detail meshes for the instances of an entity far from a view.
+///
+/// Instances closer than the `distance` of the first level are drawn with the `Handle<Mesh>` of
+/// the entity, the others with the mesh of the last level they are past. Distances are measured
+/// from each view to the center of each instance, so shadow views pick levels from the position
+/// of their light.
+///
+/// Needs [`InstanceData::position`], and is ignored for entities with [`GpuCulling`]. An
+/// [`InstanceImpostor`] on the entity is drawn as one more level, after every other level.
+#[derive(Component, Clone, Default)]
+pub struct InstanceLod {
+    /// Ordered by increasing distance.
+    pub levels: Vec<InstanceLodLevel>,
+}
+
+#[derive(Clone)]
+pub struct InstanceLodLevel {
+    pub mesh: Handle<Mesh>,
+    pub distance: f32,
+}
+
+impl InstanceLod {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a level drawing `mesh` from `distance` on.
+    pub fn with_level(mut self, mesh: Handle<Mesh>, distance: f32) -> Self {
+        self.levels.push(InstanceLodLevel { mesh, distance });
+        self
+    }
+}
+
+/// Render world [`InstanceLod`]: an entity drawing each level, and the distance it starts at.
+///
+/// The level entities only live in the render world, with the material and transform of the
+/// instanced entity and the mesh of their level. [`cull_instances`](crate::cull_instances) gives
+/// them the instances of their level in each view.
+#[derive(Component, Clone)]
+pub struct ExtractedInstanceLod {
+    pub levels: Vec<(Entity, f32)>,
+}
+
+impl ExtractedInstanceLod {
+    /// Index into `levels` of an instance at `distance` from a view, `None` for the entity mesh.
+    pub fn level(&self, distance: f32) -> Option<usize> {
+        self.levels.iter().rposition(|(_, level_distance)| distance >= *level_distance)
+    }
+}
+
+/// Same as bevy's `extract_meshes`, for the level entities which don't exist in the main world.
+fn lod_mesh_uniform(transform: &GlobalTransform, not_shadow_receiver: bool) -> MeshUniform {
+    let transform = transform.compute_matrix();
+    let mut flags = if not_shadow_receiver {
+        MeshFlags::empty()
+    } else {
+        MeshFlags::SHADOW_RECEIVER
+    };
+    if Mat3A::from_mat4(transform).determinant().is_sign_positive() {
+        flags |= MeshFlags::SIGN_DETERMINANT_MODEL_3X3;
+    }
+    MeshUniform {
+        flags: flags.bits(),
+        transform,
+        inverse_transpose_model: transform.inverse().transpose(),
+    }
+}
+
+/// Extracts the [`InstanceLod`] and [`InstanceImpostor`] of visible entities with instances of `D`
+/// and a `Handle<M>`, spawning a render world entity for each level.
+///
+/// bevy clears the render world entities at the end of every frame, so the level entities are
+/// spawned again each frame, like every extracted entity: one entity with a material, mesh and
+/// [`MeshUniform`] per level of each visible entity. Their instances don't depend on them, the
+/// view buffers of each level are kept by [`cull_instances`](crate::cull_instances) across frames
+/// and only uploaded again when they change.
+pub fn extract_instance_lods<M: Material, D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    query: Extract<Query<
+        (
+            Entity,
+            &ComputedVisibility,
+            Option<&InstanceLod>,
+            Option<&InstanceImpostor>,
+            &Handle<M>,
+            &GlobalTransform,
+            Option<&NotShadowReceiver>,
+        ),
+        (
+            With<InstanceDataVec<D>>,
+            Or<(With<InstanceLod>, With<InstanceImpostor>)>,
+            Without<GpuCulling>,
+        ),
+    >>,
+) {
+    if D::zeroed().position().is_none() {
+        return;
+    }
+
+    let impostors = !matches!(D::impostor_vertex_shader(), ShaderRef::Default);
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, lod, impostor, material_handle, transform, not_shadow_receiver) in &query {
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+        let mesh_uniform = lod_mesh_uniform(transform, not_shadow_receiver.is_some());
+        let mut levels: Vec<_> = lod.iter()
+            .flat_map(|lod| &lod.levels)
+            .map(|level| {
+                let level_entity = commands.spawn((
+                    material_handle.clone_weak(),
+                    level.mesh.clone_weak(),
+                    mesh_uniform.clone(),
+                )).id();
+                (level_entity, level.distance)
+            })
+            .collect();
+        if let Some(impostor) = impostor.filter(|_| impostors) {
+            // Drawn by `queue_instance_impostors` only, it has no material.
+            let level_entity = commands.spawn((
+                ImpostorLevel { atlas: impostor.impostor.atlas.clone_weak() },
+                impostor.impostor.mesh.clone_weak(),
+                mesh_uniform.clone(),
+            )).id();
+            levels.push((level_entity, impostor.distance));
+        }
+        if levels.is_empty() {
+            continue;
+        }
+        values.push((entity, ExtractedInstanceLod { levels }));
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lod(distances: &[f32]) -> ExtractedInstanceLod {
+        ExtractedInstanceLod {
+            levels: distances.iter()
+                .enumerate()
+                .map(|(index, distance)| (Entity::from_raw(index as u32), *distance))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn levels_start_exactly_at_their_distance() {
+        let lod = lod(&[10.0, 20.0]);
+        assert_eq!(lod.level(9.99), None);
+        assert_eq!(lod.level(10.0), Some(0));
+        assert_eq!(lod.level(19.99), Some(0));
+        assert_eq!(lod.level(20.0), Some(1));
+    }
+
+    #[test]
+    fn instances_beyond_the_last_level_keep_it() {
+        let lod = lod(&[10.0, 20.0]);
+        assert_eq!(lod.level(1000.0), Some(1));
+        assert_eq!(lod.level(f32::INFINITY), Some(1));
+    }
+
+    #[test]
+    fn without_levels_instances_keep_the_entity_mesh() {
+        let lod = lod(&[]);
+        assert_eq!(lod.level(0.0), None);
+        assert_eq!(lod.level(1000.0), None);
+    }
+}
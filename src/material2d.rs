@@ -0,0 +1,221 @@
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        mesh::MeshVertexBufferLayout,
+        render_asset::{PrepareAssetLabel, RenderAssets},
+        render_phase::{AddRenderCommand, DrawFunctions, RenderPhase, SetItemPipeline},
+        render_resource::{
+            PipelineCache, RenderPipelineDescriptor, SpecializedMeshPipeline
+        },
+        view::{ExtractedView, VisibleEntities},
+        Extract, RenderApp, RenderStage,
+    },
+    sprite::{
+        extract_materials_2d, prepare_materials_2d, ExtractedMaterials2d, Material2d, Material2dKey, Material2dPipeline,
+        Mesh2dHandle, Mesh2dPipelineKey, Mesh2dUniform, RenderMaterials2d, SetMaterial2dBindGroup,
+        SetMesh2dBindGroup, SetMesh2dViewBindGroup
+    },
+    core_pipeline::{core_2d::Transparent2d, tonemapping::Tonemapping},
+    utils::FloatOrd,
+};
+
+use crate::{
+    add_instance_data, push_instance_buffer_layout, push_instance_stream_layouts, DrawMeshInstanced,
+    ExtractedInstanceData, InstanceData, InstanceDataVec, InstancePipelineError, InstancePipelineKey,
+    InstanceStreams, SpecializedInstancePipeline, SpecializedInstancePipelines
+};
+
+/// Draws entities with a [`Mesh2dHandle`], a `Handle<M>` and an [`InstanceDataVec<D>`] into the
+/// [`Transparent2d`] phase, one instanced draw per entity.
+///
+/// `M` is prepared by this plugin, so it must not also be added through bevy's
+/// `Material2dPlugin`.
+pub struct InstanceMaterial2dPlugin<M: Material2d, D: InstanceData> {
+    pub _marker: PhantomData<(M, D)>,
+}
+
+impl<M: Material2d, D: InstanceData> Default for InstanceMaterial2dPlugin<M, D> {
+    fn default() -> Self {
+        InstanceMaterial2dPlugin {
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<M: Material2d, D: InstanceData> Plugin for InstanceMaterial2dPlugin<M, D>
+where
+    M::Data: PartialEq + Eq + Hash + Clone
+{
+    fn build(&self, app: &mut App) {
+        add_instance_data::<D>(app);
+        app.add_asset::<M>()
+            .add_plugin(ExtractComponentPlugin::<Handle<M>>::extract_visible());
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ExtractedMaterials2d<M>>()
+            .init_resource::<RenderMaterials2d<M>>()
+            .add_system_to_stage(RenderStage::Extract, extract_materials_2d::<M>)
+            .add_system_to_stage(RenderStage::Extract, extract_instanced_mesh2d_handles::<D>)
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_materials_2d::<M>.after(PrepareAssetLabel::PreAssetPrepare),
+            )
+            .add_render_command::<Transparent2d, DrawInstancedMaterial2d<M>>()
+            .init_resource::<InstanceMaterial2dPipeline<M, D>>()
+            .init_resource::<SpecializedInstancePipelines<InstanceMaterial2dPipeline<M, D>>>()
+            .add_system_to_stage(RenderStage::Queue, queue_instance_material2d_meshes::<M, D>);
+    }
+}
+
+/// Extracts the mesh of visible 2d entities with instances of `D` as a `Handle<Mesh>`, which is
+/// what [`DrawMeshInstanced`] draws.
+pub fn extract_instanced_mesh2d_handles<D: InstanceData>(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    query: Extract<Query<(Entity, &ComputedVisibility, &Mesh2dHandle), With<InstanceDataVec<D>>>>,
+) {
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, mesh2d_handle) in &query {
+        if computed_visibility.is_visible() {
+            values.push((entity, mesh2d_handle.0.clone_weak()));
+        }
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+type DrawInstancedMaterial2d<M> = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    SetMaterial2dBindGroup<M, 1>,
+    SetMesh2dBindGroup<2>,
+    DrawMeshInstanced<false>,
+);
+
+fn queue_instance_material2d_meshes<M: Material2d, D: InstanceData>(
+    transparent_draw_functions: Res<DrawFunctions<Transparent2d>>,
+    instance_material2d_pipeline: Res<InstanceMaterial2dPipeline<M, D>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstanceMaterial2dPipeline<M, D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials2d<M>>,
+    instance_material2d_meshes: Query<
+        (&Handle<M>, &Handle<Mesh>, &Mesh2dUniform, Option<&InstanceStreams>),
+        With<ExtractedInstanceData<D>>,
+    >,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        Option<&Tonemapping>,
+        &mut RenderPhase<Transparent2d>,
+    )>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    if instance_material2d_meshes.is_empty() {
+        return;
+    }
+
+    for (view, visible_entities, tonemapping, mut transparent_phase) in &mut views {
+        let draw_transparent = transparent_draw_functions.read().id::<DrawInstancedMaterial2d<M>>();
+
+        let mut view_key =
+            Mesh2dPipelineKey::from_msaa_samples(msaa.samples) | Mesh2dPipelineKey::from_hdr(view.hdr);
+
+        if let Some(Tonemapping::Enabled { deband_dither }) = tonemapping {
+            if !view.hdr {
+                view_key |= Mesh2dPipelineKey::TONEMAP_IN_SHADER;
+
+                if *deband_dither {
+                    view_key |= Mesh2dPipelineKey::DEBAND_DITHER;
+                }
+            }
+        }
+
+        for visible_entity in &visible_entities.entities {
+            let (
+                material_handle,
+                mesh_handle,
+                mesh2d_uniform,
+                instance_streams
+            ) = match instance_material2d_meshes.get(*visible_entity) {
+                Ok(mesh) => mesh,
+                Err(_) => continue,
+            };
+            let material = match render_materials.get(material_handle) {
+                Some(material) => material,
+                None => continue,
+            };
+            let mesh = match render_meshes.get(mesh_handle) {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+
+            let mesh_key = view_key | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline_id = pipelines.specialize(
+                &pipeline_cache,
+                &instance_material2d_pipeline,
+                InstancePipelineKey::new(
+                    Material2dKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                    instance_streams,
+                ),
+                &mesh.layout,
+            );
+            let pipeline_id = match pipeline_id {
+                Some(id) => id,
+                None => continue,
+            };
+
+            transparent_phase.add(Transparent2d {
+                entity: *visible_entity,
+                draw_function: draw_transparent,
+                pipeline: pipeline_id,
+                // The z of the entity, the instances themselves aren't sorted.
+                sort_key: FloatOrd(mesh2d_uniform.transform.w_axis.z),
+                batch_range: None,
+            });
+        }
+    }
+}
+
+/// bevy's [`Material2dPipeline`] with the instance buffer of `D`.
+#[derive(Resource)]
+pub struct InstanceMaterial2dPipeline<M: Material2d, D: InstanceData> {
+    pub material2d_pipeline: Material2dPipeline<M>,
+    _data: PhantomData<D>,
+}
+
+impl<M: Material2d, D: InstanceData> FromWorld for InstanceMaterial2dPipeline<M, D> {
+    fn from_world(world: &mut World) -> Self {
+        InstanceMaterial2dPipeline {
+            material2d_pipeline: Material2dPipeline::from_world(world),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<M: Material2d, D: InstanceData> SpecializedInstancePipeline for InstanceMaterial2dPipeline<M, D>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = InstancePipelineKey<Material2dKey<M>>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError> {
+        let mut descriptor = self.material2d_pipeline.specialize(key.key, layout)?;
+        push_instance_buffer_layout::<D>(&mut descriptor)?;
+        push_instance_stream_layouts(&mut descriptor, &key.stream_layouts)?;
+        Ok(descriptor)
+    }
+}
@@ -0,0 +1,679 @@
+use std::hash::Hash;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use bevy::{
+    prelude::*,
+    asset::load_internal_asset,
+    ecs::system::{lifetimeless::{Read, SQuery, SRes}, SystemParamItem},
+    render::{
+        camera::ExtractedCamera,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::{
+            sort_phase_system, CachedRenderPipelinePhaseItem, DrawFunctionId, DrawFunctions, PhaseItem,
+            RenderCommand, RenderCommandResult, RenderPhase, SetItemPipeline, TrackedRenderPass
+        },
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+            BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferDescriptor, BufferUsages,
+            CachedRenderPipelineId, ColorTargetState, ColorWrites, DynamicUniformBuffer, Extent3d,
+            FragmentState, ImageCopyBuffer, ImageCopyTexture, ImageDataLayout, LoadOp, MapMode, Operations,
+            Origin3d, PipelineCache, RenderPassColorAttachment, RenderPassDepthStencilAttachment,
+            RenderPassDescriptor, RenderPipelineDescriptor, ShaderStages, ShaderType,
+            TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{CachedTexture, TextureCache},
+        view::{ExtractedView, ViewUniformOffset, VisibleEntities},
+        Extract, RenderApp, RenderStage,
+    },
+    pbr::{
+        MaterialPipelineKey, MeshPipelineKey, MeshUniform, MeshViewBindGroup, RenderMaterials, SetMaterialBindGroup,
+        SetMeshBindGroup
+    },
+    core_pipeline::core_3d,
+    utils::{FloatOrd, HashMap},
+};
+
+use crate::{
+    DrawMeshInstanced, ExtractedInstanceData, GpuCulling, InstanceBindGroups, InstanceData,
+    InstanceMaterialPipeline, InstancePipelineKey, InstanceStreams, SetInstanceBindGroup,
+    SpecializedInstancePipelines
+};
+
+pub const INSTANCE_PICKING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 11558930627241306853);
+
+/// Sets up the picking pass of cameras with an [`InstancePicking`]. Added by
+/// [`InstanceMaterialPlugin`](crate::InstanceMaterialPlugin) when
+/// [`picking`](crate::InstanceMaterialPlugin::picking) is set.
+pub struct InstancePickingPlugin;
+
+impl Plugin for InstancePickingPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCE_PICKING_SHADER_HANDLE,
+            "instance_picking.wgsl",
+            Shader::from_wgsl
+        );
+
+        let results = InstancePickingResults::default();
+        app.insert_resource(results.clone())
+            .add_system_to_stage(CoreStage::PreUpdate, receive_instance_picks);
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .insert_resource(results)
+            .init_resource::<InstancePickingLayout>()
+            .init_resource::<InstancePickingIds>()
+            .init_resource::<InstancePickingReadbacks>()
+            .init_resource::<DrawFunctions<InstancePicking3d>>()
+            .add_system_to_stage(RenderStage::Extract, extract_instance_picking)
+            .add_system_to_stage(RenderStage::Prepare, prepare_instance_picking)
+            // After every `queue_instance_picking` handed out its ids.
+            .add_system_to_stage(RenderStage::PhaseSort, prepare_instance_picking_ids)
+            .add_system_to_stage(RenderStage::PhaseSort, sort_phase_system::<InstancePicking3d>)
+            .add_system_to_stage(RenderStage::Cleanup, read_back_instance_picks);
+
+        let picking_node = InstancePickingNode::new(&mut render_app.world);
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        let draw_3d_graph = render_graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+        draw_3d_graph.add_node(InstancePickingNode::NAME, picking_node);
+        draw_3d_graph
+            .add_slot_edge(
+                draw_3d_graph.input_node().unwrap().id,
+                core_3d::graph::input::VIEW_ENTITY,
+                InstancePickingNode::NAME,
+                InstancePickingNode::IN_VIEW,
+            )
+            .unwrap();
+        draw_3d_graph
+            .add_node_edge(core_3d::graph::node::MAIN_PASS, InstancePickingNode::NAME)
+            .unwrap();
+    }
+}
+
+/// Picks the instanced entity and the instance drawn at `position` by a camera.
+///
+/// The camera renders the entities of materials added with
+/// [`picking`](crate::InstanceMaterialPlugin::picking) into an `Rg32Uint` target, one pick id
+/// for the entity and the index of the instance in its instance buffer, and reads back the texel
+/// at `position`. Instances are picked with their full mesh, whatever their
+/// [`InstanceLod`](crate::InstanceLod), and entities with [`GpuCulling`] can't be picked.
+#[derive(Component, Clone, Default)]
+pub struct InstancePicking {
+    /// Position to pick at in logical pixels, from the top left corner of the viewport. `None`
+    /// skips the picking pass of the camera.
+    pub position: Option<Vec2>,
+    /// What was drawn at `position`, a few frames late since it is read back from the gpu.
+    /// Cleared while `position` is `None`.
+    pub hit: Option<InstancePick>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstancePick {
+    pub entity: Entity,
+    /// Index of the instance in the [`InstanceDataVec`](crate::InstanceDataVec) of `entity`.
+    pub instance_index: u32,
+}
+
+/// Picks read back by the render world for each camera, shared with the main world.
+#[derive(Resource, Clone, Default)]
+pub struct InstancePickingResults(Arc<Mutex<Vec<(Entity, Option<InstancePick>)>>>);
+
+pub fn receive_instance_picks(
+    results: Res<InstancePickingResults>,
+    mut cameras: Query<&mut InstancePicking>,
+) {
+    for mut picking in &mut cameras {
+        if picking.position.is_none() && picking.hit.is_some() {
+            picking.hit = None;
+        }
+    }
+    for (camera, pick) in results.0.lock().unwrap().drain(..) {
+        if let Ok(mut picking) = cameras.get_mut(camera) {
+            picking.hit = picking.position.and(pick);
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ExtractedInstancePicking {
+    /// In physical pixels, from the top left corner of the viewport.
+    pub position: UVec2,
+}
+
+pub fn extract_instance_picking(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera, &InstancePicking)>>,
+) {
+    for (entity, camera, picking) in &cameras {
+        let (position, logical_size, physical_size) = match (
+            picking.position,
+            camera.logical_viewport_size(),
+            camera.physical_viewport_size(),
+        ) {
+            (Some(position), Some(logical_size), Some(physical_size)) if camera.is_active => {
+                (position, logical_size, physical_size)
+            }
+            _ => continue,
+        };
+        let position = position * physical_size.as_vec2() / logical_size;
+        if position.cmplt(Vec2::ZERO).any() || position.cmpge(physical_size.as_vec2()).any() {
+            continue;
+        }
+        commands.get_or_spawn(entity).insert(ExtractedInstancePicking {
+            position: position.as_uvec2(),
+        });
+    }
+}
+
+/// The picking target of a camera view, and the render world entity holding its
+/// [`InstancePicking3d`] phase.
+///
+/// Items are drawn from that separate view entity so they use the whole instance buffer of their
+/// entity, instead of the instances culled for the camera, which would change their indices.
+#[derive(Component)]
+pub struct ViewInstancePicking {
+    pub picking_view: Entity,
+    pub texture: CachedTexture,
+    pub depth: CachedTexture,
+    pub readback: PickingReadback,
+}
+
+/// The buffer the picked texel of a camera is copied to and mapped from.
+#[derive(Clone)]
+pub struct PickingReadback {
+    pub buffer: Buffer,
+    /// Set from the call to `map_async` until the buffer is read and unmapped.
+    mapping: Arc<AtomicBool>,
+}
+
+/// The [`PickingReadback`] of each picking camera, reused across frames.
+#[derive(Resource, Default)]
+pub struct InstancePickingReadbacks(HashMap<Entity, PickingReadback>);
+
+/// The camera view a picking view draws for.
+#[derive(Component)]
+pub struct PickingView {
+    pub view: Entity,
+}
+
+pub fn prepare_instance_picking(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    mut picking_ids: ResMut<InstancePickingIds>,
+    mut picking_readbacks: ResMut<InstancePickingReadbacks>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera), With<ExtractedInstancePicking>>,
+) {
+    picking_ids.clear();
+    picking_readbacks.0.retain(|camera, _| views.contains(*camera));
+
+    for (entity, camera) in &views {
+        let size = match camera.physical_viewport_size {
+            Some(size) => size,
+            None => continue,
+        };
+        let readback = picking_readbacks.0.entry(entity).or_insert_with(|| PickingReadback {
+            buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("instance_picking_readback"),
+                size: std::mem::size_of::<[u32; 2]>() as u64,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            mapping: Default::default(),
+        });
+        // A mapped buffer can't be copied to, the camera skips picking until the last pick is read.
+        if readback.mapping.load(Ordering::Acquire) {
+            continue;
+        }
+
+        let size = Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        };
+        let texture_descriptor = |label, format, usage| TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage,
+        };
+        let texture = texture_cache.get(
+            &render_device,
+            texture_descriptor(
+                "instance_picking_texture",
+                TextureFormat::Rg32Uint,
+                TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            ),
+        );
+        let depth = texture_cache.get(
+            &render_device,
+            texture_descriptor(
+                "instance_picking_depth",
+                TextureFormat::Depth32Float,
+                TextureUsages::RENDER_ATTACHMENT,
+            ),
+        );
+        let picking_view = commands.spawn((
+            PickingView { view: entity },
+            RenderPhase::<InstancePicking3d>::default(),
+        )).id();
+        commands.entity(entity).insert(ViewInstancePicking {
+            picking_view,
+            texture,
+            depth,
+            readback: readback.clone(),
+        });
+    }
+}
+
+#[derive(Clone, ShaderType)]
+pub struct InstancePickingUniform {
+    pub id: u32,
+}
+
+/// Pick ids of the entities drawn into picking views this frame: id `n` is the `n - 1`th entity,
+/// `0` is left where nothing was drawn.
+#[derive(Resource, Default)]
+pub struct InstancePickingIds {
+    entities: Vec<Entity>,
+    offsets: HashMap<Entity, u32>,
+    uniforms: DynamicUniformBuffer<InstancePickingUniform>,
+    bind_group: Option<BindGroup>,
+}
+
+impl InstancePickingIds {
+    /// Dynamic offset of the picking uniform of `entity`.
+    pub fn offset(&mut self, entity: Entity) -> u32 {
+        let InstancePickingIds { entities, offsets, uniforms, .. } = self;
+        *offsets.entry(entity).or_insert_with(|| {
+            entities.push(entity);
+            uniforms.push(InstancePickingUniform { id: entities.len() as u32 })
+        })
+    }
+
+    fn clear(&mut self) {
+        self.entities.clear();
+        self.offsets.clear();
+        self.uniforms.clear();
+        self.bind_group = None;
+    }
+}
+
+#[derive(Resource)]
+pub struct InstancePickingLayout {
+    pub layout: BindGroupLayout,
+}
+
+impl FromWorld for InstancePickingLayout {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_picking_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(InstancePickingUniform::min_size()),
+                },
+                count: None,
+            }],
+        });
+        InstancePickingLayout { layout }
+    }
+}
+
+pub fn prepare_instance_picking_ids(
+    mut picking_ids: ResMut<InstancePickingIds>,
+    picking_layout: Res<InstancePickingLayout>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if picking_ids.entities.is_empty() {
+        return;
+    }
+    picking_ids.uniforms.write_buffer(&render_device, &render_queue);
+    let binding = match picking_ids.uniforms.binding() {
+        Some(binding) => binding,
+        None => return,
+    };
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("instance_picking_bind_group"),
+        layout: &picking_layout.layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: binding,
+        }],
+    });
+    picking_ids.bind_group = Some(bind_group);
+}
+
+/// Turns an instanced pipeline into its picking variant: the `INSTANCE_PICKING` def, under which
+/// the vertex shader outputs the instance index at `@location(9) @interpolate(flat)`, and a
+/// fragment shader writing the pick id and that index into an `Rg32Uint` target.
+///
+/// `picking_layout` goes after the bind groups already in `descriptor`.
+pub fn use_instance_picking(descriptor: &mut RenderPipelineDescriptor, picking_layout: &BindGroupLayout) {
+    descriptor.vertex.shader_defs.push("INSTANCE_PICKING".to_string());
+    descriptor.layout.get_or_insert_with(Vec::new).push(picking_layout.clone());
+    descriptor.fragment = Some(FragmentState {
+        shader: INSTANCE_PICKING_SHADER_HANDLE.typed::<Shader>(),
+        shader_defs: descriptor.vertex.shader_defs.clone(),
+        entry_point: "fragment".into(),
+        targets: vec![Some(ColorTargetState {
+            format: TextureFormat::Rg32Uint,
+            blend: None,
+            write_mask: ColorWrites::ALL,
+        })],
+    });
+    // Transparent materials are picked too, the closest one wins.
+    if let Some(depth_stencil) = &mut descriptor.depth_stencil {
+        depth_stencil.depth_write_enabled = true;
+    }
+}
+
+pub struct InstancePicking3d {
+    pub distance: f32,
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+    /// Dynamic offset of the picking uniform of `entity`.
+    pub picking_offset: u32,
+}
+
+impl PhaseItem for InstancePicking3d {
+    type SortKey = FloatOrd;
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        FloatOrd(self.distance)
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for InstancePicking3d {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+pub type DrawInstancedPicking<M> = (
+    SetItemPipeline,
+    SetPickingViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    SetPickingBindGroup,
+    DrawMeshInstanced<true>,
+);
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_instance_picking<M: Material, D: InstanceData>(
+    picking_draw_functions: Res<DrawFunctions<InstancePicking3d>>,
+    instance_material_pipeline: Res<InstanceMaterialPipeline<M, D>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstanceMaterialPipeline<M, D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<M>>,
+    mut picking_ids: ResMut<InstancePickingIds>,
+    instance_material_meshes: Query<
+        (&Handle<M>, &Handle<Mesh>, &MeshUniform, Option<&InstanceStreams>),
+        (With<ExtractedInstanceData<D>>, Without<GpuCulling>),
+    >,
+    views: Query<(&ExtractedView, &VisibleEntities, &ViewInstancePicking)>,
+    mut picking_phases: Query<&mut RenderPhase<InstancePicking3d>>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    let draw_picking = picking_draw_functions.read().id::<DrawInstancedPicking<M>>();
+
+    for (view, visible_entities, view_picking) in &views {
+        let mut picking_phase = match picking_phases.get_mut(view_picking.picking_view) {
+            Ok(picking_phase) => picking_phase,
+            Err(_) => continue,
+        };
+        let rangefinder = view.rangefinder3d();
+
+        for visible_entity in &visible_entities.entities {
+            let (material_handle, mesh_handle, mesh_uniform, instance_streams) =
+                match instance_material_meshes.get(*visible_entity) {
+                    Ok(instance_mesh) => instance_mesh,
+                    Err(_) => continue,
+                };
+            let (material, mesh) = match (render_materials.get(material_handle), render_meshes.get(mesh_handle)) {
+                (Some(material), Some(mesh)) => (material, mesh),
+                _ => continue,
+            };
+
+            // The picking target isn't multisampled, and the fragment shader is replaced.
+            let mesh_key = MeshPipelineKey::from_msaa_samples(1)
+                | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let key = InstancePipelineKey {
+                picking: true,
+                ..InstancePipelineKey::new(
+                    MaterialPipelineKey {
+                        mesh_key,
+                        bind_group_data: material.key.clone(),
+                    },
+                    instance_streams,
+                )
+            };
+            let pipeline_id = pipelines.specialize(
+                &pipeline_cache,
+                &instance_material_pipeline,
+                key,
+                &mesh.layout,
+            );
+            let pipeline_id = match pipeline_id {
+                Some(id) => id,
+                None => continue,
+            };
+
+            picking_phase.add(InstancePicking3d {
+                distance: rangefinder.distance(&mesh_uniform.transform),
+                entity: *visible_entity,
+                pipeline: pipeline_id,
+                draw_function: draw_picking,
+                picking_offset: picking_ids.offset(*visible_entity),
+            });
+        }
+    }
+}
+
+/// Binds the view of the camera a picking view draws for.
+pub struct SetPickingViewBindGroup<const I: usize>;
+
+impl<const I: usize> RenderCommand<InstancePicking3d> for SetPickingViewBindGroup<I> {
+    type Param = SQuery<(Read<ViewUniformOffset>, Read<MeshViewBindGroup>)>;
+    type ViewWorldQuery = Read<PickingView>;
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &InstancePicking3d,
+        picking_view: &'w PickingView,
+        _entity: (),
+        views: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let (view_uniform, mesh_view_bind_group) = match views.get_inner(picking_view.view) {
+            Ok(view) => view,
+            Err(_) => return RenderCommandResult::Failure,
+        };
+        pass.set_bind_group(I, &mesh_view_bind_group.value, &[view_uniform.offset]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Binds the picking uniform of the entity, after the instance storage bind group if any.
+pub struct SetPickingBindGroup;
+
+impl RenderCommand<InstancePicking3d> for SetPickingBindGroup {
+    type Param = SRes<InstancePickingIds>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Option<Read<InstanceBindGroups>>;
+
+    #[inline]
+    fn render<'w>(
+        item: &InstancePicking3d,
+        _view: (),
+        instance_bind_groups: Option<&'w InstanceBindGroups>,
+        picking_ids: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let bind_group = match &picking_ids.into_inner().bind_group {
+            Some(bind_group) => bind_group,
+            None => return RenderCommandResult::Failure,
+        };
+        let index = if instance_bind_groups.is_some() { 4 } else { 3 };
+        pass.set_bind_group(index, bind_group, &[item.picking_offset]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Draws the [`InstancePicking3d`] phase of a camera view after its main pass, and copies the
+/// picked texel to its readback buffer.
+pub struct InstancePickingNode {
+    query: QueryState<(&'static ViewInstancePicking, &'static ExtractedInstancePicking)>,
+}
+
+impl InstancePickingNode {
+    pub const NAME: &'static str = "instance_picking";
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        InstancePickingNode {
+            query: world.query(),
+        }
+    }
+}
+
+impl Node for InstancePickingNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let (view_picking, picking) = match self.query.get_manual(world, view_entity) {
+            Ok(view) => view,
+            Err(_) => return Ok(()),
+        };
+        let picking_phase = match world.get::<RenderPhase<InstancePicking3d>>(view_picking.picking_view) {
+            Some(picking_phase) => picking_phase,
+            None => return Ok(()),
+        };
+
+        {
+            let render_pass = render_context.command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("instance_picking_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view_picking.texture.default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Default::default()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &view_picking.depth.default_view,
+                    // bevy uses reverse z, 0.0 is the far plane.
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(0.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            let mut render_pass = TrackedRenderPass::new(render_pass);
+            picking_phase.render(&mut render_pass, world, view_picking.picking_view);
+        }
+
+        render_context.command_encoder.copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &view_picking.texture.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: picking.position.x,
+                    y: picking.position.y,
+                    z: 0,
+                },
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &view_picking.readback.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Maps the readback buffers once the frame is submitted, sending what they hold to the main
+/// world when they are mapped, and unmaps them for the next pick.
+pub fn read_back_instance_picks(
+    picking_ids: Res<InstancePickingIds>,
+    results: Res<InstancePickingResults>,
+    views: Query<(Entity, &ViewInstancePicking)>,
+) {
+    for (camera, view_picking) in &views {
+        let readback = view_picking.readback.clone();
+        let entities = picking_ids.entities.clone();
+        let results = results.clone();
+        readback.mapping.store(true, Ordering::Release);
+        view_picking.readback.buffer.slice(..).map_async(MapMode::Read, move |result| {
+            if result.is_ok() {
+                let [id, instance_index]: [u32; 2] =
+                    bytemuck::pod_read_unaligned(&readback.buffer.slice(..).get_mapped_range());
+                let pick = id.checked_sub(1)
+                    .and_then(|index| entities.get(index as usize))
+                    .map(|entity| InstancePick {
+                        entity: *entity,
+                        instance_index,
+                    });
+                results.0.lock().unwrap().push((camera, pick));
+                readback.buffer.unmap();
+            }
+            readback.mapping.store(false, Ordering::Release);
+        });
+    }
+}
@@ -0,0 +1,338 @@
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_phase::{DrawFunctions, RenderPhase, SetItemPipeline},
+        render_resource::{
+            BindGroupLayout, BlendState, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState,
+            DepthStencilState, FragmentState, FrontFace, MultisampleState, PipelineCache, PolygonMode,
+            PrimitiveState, RenderPipelineDescriptor, ShaderRef, StencilFaceState, StencilState, VertexState
+        },
+        view::{ExtractedView, VisibleEntities},
+    },
+    pbr::{
+        MaterialPipeline, MaterialPipelineKey, MeshPipelineKey, MeshUniform, PrepassPipeline, RenderMaterials,
+        SetMaterialBindGroup, SetMeshBindGroup, SetPrepassViewBindGroup, PREPASS_SHADER_HANDLE
+    },
+    core_pipeline::prepass::{
+        AlphaMask3dPrepass, DepthPrepass, NormalPrepass, Opaque3dPrepass, DEPTH_PREPASS_FORMAT, NORMAL_PREPASS_FORMAT
+    },
+};
+
+use crate::{
+    animated_by_texture, push_instance_input, push_instance_stream_layouts, DrawMeshInstanced,
+    ExtractedInstanceData, ExtractedInstanceLod, ImpostorLevel, InstanceData, InstancePipelineError,
+    InstancePipelineKey, InstanceStorageLayout, InstanceStreams, SetInstanceBindGroup, SpecializedInstancePipeline,
+    SpecializedInstancePipelines
+};
+
+pub const INSTANCED_PREPASS_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3971886618350414257);
+
+pub type DrawInstancedPrepass<M> = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMaterialBindGroup<M, 1>,
+    SetMeshBindGroup<2>,
+    SetInstanceBindGroup<3>,
+    DrawMeshInstanced<true>,
+);
+
+/// bevy's [`PrepassPipeline`] with the vertex shader of [`InstanceData::prepass_vertex_shader`]
+/// and the instance buffer of `D`.
+#[derive(Resource)]
+pub struct InstancePrepassPipeline<M: Material, D: InstanceData> {
+    pub view_layout: BindGroupLayout,
+    pub mesh_layout: BindGroupLayout,
+    pub skinned_mesh_layout: BindGroupLayout,
+    pub material_layout: BindGroupLayout,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub material_fragment_shader: Option<Handle<Shader>>,
+    pub material_pipeline: MaterialPipeline<M>,
+    pub instance_storage_layout: Option<BindGroupLayout>,
+    _data: PhantomData<D>,
+}
+
+impl<M: Material, D: InstanceData> FromWorld for InstancePrepassPipeline<M, D> {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        // `PrepassPipeline` can't be cloned or built outside of bevy, so only its layouts and
+        // shaders are reused and `specialize` mirrors it.
+        let prepass_pipeline = world.resource::<PrepassPipeline<M>>();
+
+        InstancePrepassPipeline {
+            view_layout: prepass_pipeline.view_layout.clone(),
+            mesh_layout: prepass_pipeline.mesh_layout.clone(),
+            skinned_mesh_layout: prepass_pipeline.skinned_mesh_layout.clone(),
+            material_layout: prepass_pipeline.material_layout.clone(),
+            vertex_shader: match D::prepass_vertex_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            material_fragment_shader: prepass_pipeline.material_fragment_shader.clone(),
+            material_pipeline: prepass_pipeline.material_pipeline.clone(),
+            instance_storage_layout: world.get_resource::<InstanceStorageLayout<D>>()
+                .map(|storage_layout| storage_layout.layout.clone()),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<M: Material, D: InstanceData> SpecializedInstancePipeline for InstancePrepassPipeline<M, D>
+where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    type Key = InstancePipelineKey<MaterialPipelineKey<M>>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError> {
+        let InstancePipelineKey { key, stream_layouts, .. } = key;
+        let mut bind_group_layout = vec![self.view_layout.clone(), self.material_layout.clone()];
+        let mut shader_defs = Vec::new();
+        let mut vertex_attributes = Vec::new();
+
+        if key.mesh_key.contains(MeshPipelineKey::DEPTH_PREPASS) {
+            shader_defs.push(String::from("DEPTH_PREPASS"));
+        }
+        if key.mesh_key.contains(MeshPipelineKey::ALPHA_MASK) {
+            shader_defs.push(String::from("ALPHA_MASK"));
+        }
+
+        if layout.contains(Mesh::ATTRIBUTE_POSITION) {
+            shader_defs.push(String::from("VERTEX_POSITIONS"));
+            vertex_attributes.push(Mesh::ATTRIBUTE_POSITION.at_shader_location(0));
+        }
+        if layout.contains(Mesh::ATTRIBUTE_UV_0) {
+            shader_defs.push(String::from("VERTEX_UVS"));
+            vertex_attributes.push(Mesh::ATTRIBUTE_UV_0.at_shader_location(1));
+        }
+        if key.mesh_key.contains(MeshPipelineKey::NORMAL_PREPASS) {
+            shader_defs.push(String::from("NORMAL_PREPASS"));
+            vertex_attributes.push(Mesh::ATTRIBUTE_NORMAL.at_shader_location(2));
+            if layout.contains(Mesh::ATTRIBUTE_TANGENT) {
+                shader_defs.push(String::from("VERTEX_TANGENTS"));
+                vertex_attributes.push(Mesh::ATTRIBUTE_TANGENT.at_shader_location(3));
+            }
+        }
+        if layout.contains(Mesh::ATTRIBUTE_JOINT_INDEX) && layout.contains(Mesh::ATTRIBUTE_JOINT_WEIGHT) {
+            shader_defs.push(String::from("SKINNED"));
+            vertex_attributes.push(Mesh::ATTRIBUTE_JOINT_INDEX.at_shader_location(4));
+            vertex_attributes.push(Mesh::ATTRIBUTE_JOINT_WEIGHT.at_shader_location(5));
+            bind_group_layout.push(self.skinned_mesh_layout.clone());
+        } else {
+            bind_group_layout.push(self.mesh_layout.clone());
+        }
+
+        let vertex_buffer_layout = layout.get_layout(&vertex_attributes)?;
+
+        // Same as bevy: the fragment shader only runs for the normal prepass, or for alpha masked
+        // materials with their own prepass fragment shader.
+        let fragment = if key.mesh_key.contains(MeshPipelineKey::NORMAL_PREPASS)
+            || (key.mesh_key.contains(MeshPipelineKey::ALPHA_MASK) && self.material_fragment_shader.is_some())
+        {
+            let mut targets = Vec::new();
+            if key.mesh_key.contains(MeshPipelineKey::NORMAL_PREPASS) {
+                targets.push(Some(ColorTargetState {
+                    format: NORMAL_PREPASS_FORMAT,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                }));
+            }
+            Some(FragmentState {
+                shader: self.material_fragment_shader.clone()
+                    .unwrap_or_else(|| PREPASS_SHADER_HANDLE.typed::<Shader>()),
+                entry_point: "fragment".into(),
+                shader_defs: shader_defs.clone(),
+                targets,
+            })
+        } else {
+            None
+        };
+
+        let mut descriptor = RenderPipelineDescriptor {
+            vertex: VertexState {
+                shader: self.vertex_shader.clone()
+                    .unwrap_or_else(|| PREPASS_SHADER_HANDLE.typed::<Shader>()),
+                entry_point: "vertex".into(),
+                shader_defs,
+                buffers: vec![vertex_buffer_layout],
+            },
+            fragment,
+            layout: Some(bind_group_layout),
+            primitive: PrimitiveState {
+                topology: key.mesh_key.primitive_topology(),
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: DEPTH_PREPASS_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState {
+                    front: StencilFaceState::IGNORE,
+                    back: StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState {
+                    constant: 0,
+                    slope_scale: 0.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: key.mesh_key.msaa_samples(),
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            label: Some("instanced_prepass_pipeline".into()),
+        };
+        push_instance_input::<D>(&mut descriptor, self.instance_storage_layout.as_ref())?;
+        push_instance_stream_layouts(&mut descriptor, &stream_layouts)?;
+
+        M::specialize(&self.material_pipeline, &mut descriptor, layout, key)?;
+        Ok(descriptor)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn queue_instance_prepass_meshes<M: Material, D: InstanceData>(
+    opaque_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    alpha_mask_draw_functions: Res<DrawFunctions<AlphaMask3dPrepass>>,
+    instance_prepass_pipeline: Res<InstancePrepassPipeline<M, D>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstancePrepassPipeline<M, D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<M>>,
+    instance_material_meshes: Query<
+        (&Handle<M>, &Handle<Mesh>, &MeshUniform, Option<&InstanceStreams>, Option<&ExtractedInstanceLod>),
+        With<ExtractedInstanceData<D>>,
+    >,
+    lod_meshes: Query<&Handle<Mesh>, Without<ImpostorLevel>>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<Opaque3dPrepass>,
+        &mut RenderPhase<AlphaMask3dPrepass>,
+        Option<&DepthPrepass>,
+        Option<&NormalPrepass>,
+    )>,
+) where
+    M::Data: PartialEq + Eq + Hash + Clone,
+{
+    // The default prepass vertex shader doesn't know where the instances are.
+    if instance_prepass_pipeline.vertex_shader.is_none() {
+        return;
+    }
+
+    let draw_opaque_prepass = opaque_draw_functions.read().id::<DrawInstancedPrepass<M>>();
+    let draw_alpha_mask_prepass = alpha_mask_draw_functions.read().id::<DrawInstancedPrepass<M>>();
+
+    for (
+        view,
+        visible_entities,
+        mut opaque_phase,
+        mut alpha_mask_phase,
+        depth_prepass,
+        normal_prepass,
+    ) in &mut views {
+        let mut view_key = MeshPipelineKey::from_msaa_samples(msaa.samples);
+        if depth_prepass.is_some() {
+            view_key |= MeshPipelineKey::DEPTH_PREPASS;
+        }
+        if normal_prepass.is_some() {
+            view_key |= MeshPipelineKey::NORMAL_PREPASS;
+        }
+        let rangefinder = view.rangefinder3d();
+
+        for visible_entity in &visible_entities.entities {
+            let (
+                material_handle,
+                mesh_handle,
+                mesh_uniform,
+                instance_streams,
+                lod
+            ) = match instance_material_meshes.get(*visible_entity) {
+                Ok(mesh) => mesh,
+                Err(_) => continue,
+            };
+            let material = match render_materials.get(material_handle) {
+                Some(material) => material,
+                None => continue,
+            };
+            let lod_levels = lod.iter()
+                .flat_map(|lod| &lod.levels)
+                .filter_map(|(level_entity, _)| Some((*level_entity, lod_meshes.get(*level_entity).ok()?)));
+            for (item_entity, mesh_handle) in std::iter::once((*visible_entity, mesh_handle)).chain(lod_levels) {
+                let mesh = match render_meshes.get(mesh_handle) {
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                // The prepass shader has no `ANIMATION_TEXTURE` path.
+                if animated_by_texture::<D>(&mesh.layout) {
+                    continue;
+                }
+
+                let mut mesh_key =
+                    MeshPipelineKey::from_primitive_topology(mesh.primitive_topology) | view_key;
+                let alpha_mode = material.properties.alpha_mode;
+                match alpha_mode {
+                    AlphaMode::Opaque => {}
+                    AlphaMode::Mask(_) => mesh_key |= MeshPipelineKey::ALPHA_MASK,
+                    AlphaMode::Blend => continue,
+                }
+
+                let pipeline_id = pipelines.specialize(
+                    &pipeline_cache,
+                    &instance_prepass_pipeline,
+                    InstancePipelineKey::new(
+                        MaterialPipelineKey {
+                            mesh_key,
+                            bind_group_data: material.key.clone(),
+                        },
+                        instance_streams,
+                    ),
+                    &mesh.layout,
+                );
+                let pipeline_id = match pipeline_id {
+                    Some(id) => id,
+                    None => continue,
+                };
+
+                let distance = rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
+                match alpha_mode {
+                    AlphaMode::Opaque => {
+                        opaque_phase.add(Opaque3dPrepass {
+                            entity: item_entity,
+                            draw_function: draw_opaque_prepass,
+                            pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Mask(_) => {
+                        alpha_mask_phase.add(AlphaMask3dPrepass {
+                            entity: item_entity,
+                            draw_function: draw_alpha_mask_prepass,
+                            pipeline_id,
+                            distance,
+                        });
+                    }
+                    AlphaMode::Blend => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::borrow::Cow;
+
+use bevy::{
+    prelude::*,
+    math::Ray,
+    render::{mesh::{Indices, VertexAttributeValues}, render_resource::PrimitiveTopology},
+};
+
+use crate::{mesh_radius, InstanceBvh, InstanceData, InstanceDataVec};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceRayHit {
+    pub entity: Entity,
+    /// Index of the instance in the [`InstanceDataVec`] of `entity`.
+    pub instance_index: usize,
+    /// Distance from the origin of the ray, in multiples of its direction.
+    pub distance: f32,
+}
+
+/// Intersects `ray` with every instance of the entities in `instances`, returning the hits
+/// closest first, e.g. `raycast_instances(ray, &query, &meshes, true)` with a
+/// `Query<(Entity, &InstanceDataVec<D>, &GlobalTransform, &Handle<Mesh>, Option<&InstanceBvh>)>`.
+///
+/// Instances are first tested against the boxes around their spheres, through the
+/// [`InstanceBvh`] of each entity. It is built for the cast when the entity doesn't have one,
+/// and refitted on a copy when instances changed since
+/// [`update_instance_bvhs`](crate::update_instance_bvhs) last synced it in
+/// [`CoreStage::PostUpdate`]. With `triangles`, the ray is then tested against the triangles of
+/// the mesh of the instances hit, placed by [`InstanceData::transform`]; instances without a
+/// transform or meshes without triangle lists keep the distance to their box.
+///
+/// Needs [`InstanceData::position`], entities are skipped otherwise.
+pub fn raycast_instances<'a, D: InstanceData>(
+    ray: Ray,
+    instances: impl IntoIterator<Item = (
+        Entity,
+        &'a InstanceDataVec<D>,
+        &'a GlobalTransform,
+        &'a Handle<Mesh>,
+        Option<&'a InstanceBvh>,
+    )>,
+    meshes: &Assets<Mesh>,
+    triangles: bool,
+) -> Vec<InstanceRayHit> {
+    let mut hits = Vec::new();
+    for (entity, instance_data, transform, mesh_handle, bvh) in instances {
+        let mesh = match meshes.get(mesh_handle) {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+        let bvh = match synced_bvh(bvh, instance_data, mesh) {
+            Some(bvh) => bvh,
+            None => continue,
+        };
+        raycast_bvh(ray, entity, transform, &bvh, instance_data, triangles.then_some(mesh), &mut hits);
+    }
+    hits.sort_unstable_by(|a, b| a.distance.total_cmp(&b.distance));
+    hits
+}
+
+/// `bvh` when it is synced with `instance_data`, otherwise a copy brought up to date, or one built
+/// from scratch when the entity doesn't have one, e.g. when casting in `CoreStage::Update`.
+fn synced_bvh<'a, D: InstanceData>(
+    bvh: Option<&'a InstanceBvh>,
+    instance_data: &InstanceDataVec<D>,
+    mesh: &Mesh,
+) -> Option<Cow<'a, InstanceBvh>> {
+    match bvh {
+        Some(bvh) if bvh.is_synced(instance_data) => Some(Cow::Borrowed(bvh)),
+        Some(bvh) => {
+            let mut bvh = bvh.clone();
+            bvh.sync(instance_data, mesh_radius(mesh)?);
+            Some(Cow::Owned(bvh))
+        }
+        None => InstanceBvh::from_instances(instance_data, mesh_radius(mesh)?).map(Cow::Owned),
+    }
+}
+
+/// Pushes the hits of `ray` with the instances of `entity` found through `bvh` to `hits`.
+pub(crate) fn raycast_bvh<D: InstanceData>(
+    ray: Ray,
+    entity: Entity,
+    transform: &GlobalTransform,
+    bvh: &InstanceBvh,
+    instances: &[D],
+    mesh: Option<&Mesh>,
+    hits: &mut Vec<InstanceRayHit>,
+) {
+    // Distances along the direction stay the same in local space, as long as it isn't normalized.
+    let world_to_local = transform.compute_matrix().inverse();
+    let origin = world_to_local.transform_point3(ray.origin);
+    let direction = world_to_local.transform_vector3(ray.direction);
+
+    bvh.query_ray(origin, direction, |instance_index, bounds_distance| {
+        let distance = match mesh.zip(instances[instance_index].transform()) {
+            Some((mesh, instance_transform)) => {
+                let mesh_to_local = instance_transform.inverse();
+                match ray_mesh_distance(
+                    mesh,
+                    mesh_to_local.transform_point3(origin),
+                    mesh_to_local.transform_vector3(direction),
+                ) {
+                    Some(Some(distance)) => distance,
+                    // Missed every triangle.
+                    Some(None) => return,
+                    None => bounds_distance,
+                }
+            }
+            None => bounds_distance,
+        };
+        hits.push(InstanceRayHit {
+            entity,
+            instance_index,
+            distance,
+        });
+    });
+}
+
+/// Distance to the closest triangle of `mesh` hit by the ray, `None` if `mesh` isn't made of
+/// triangles it can test.
+fn ray_mesh_distance(mesh: &Mesh, origin: Vec3, direction: Vec3) -> Option<Option<f32>> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        return None;
+    }
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION)? {
+        VertexAttributeValues::Float32x3(positions) => positions,
+        _ => return None,
+    };
+    let vertex = |index: usize| Vec3::from(positions[index]);
+
+    let mut closest: Option<f32> = None;
+    let mut test = |a: usize, b: usize, c: usize| {
+        if let Some(distance) = ray_triangle_distance(origin, direction, [vertex(a), vertex(b), vertex(c)]) {
+            closest = Some(closest.map_or(distance, |closest| closest.min(distance)));
+        }
+    };
+    match mesh.indices() {
+        Some(Indices::U16(indices)) => indices.chunks_exact(3)
+            .for_each(|triangle| test(triangle[0] as usize, triangle[1] as usize, triangle[2] as usize)),
+        Some(Indices::U32(indices)) => indices.chunks_exact(3)
+            .for_each(|triangle| test(triangle[0] as usize, triangle[1] as usize, triangle[2] as usize)),
+        None => (0..positions.len() / 3)
+            .for_each(|triangle| test(triangle * 3, triangle * 3 + 1, triangle * 3 + 2)),
+    }
+    Some(closest)
+}
+
+/// Möller–Trumbore intersection, hitting both faces.
+fn ray_triangle_distance(origin: Vec3, direction: Vec3, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+    let edge_1 = b - a;
+    let edge_2 = c - a;
+    let p = direction.cross(edge_2);
+    let determinant = edge_1.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let inverse_determinant = determinant.recip();
+    let s = origin - a;
+    let u = s.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge_1);
+    let v = direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = edge_2.dot(q) * inverse_determinant;
+    (distance >= 0.0).then_some(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StandardInstanceData;
+
+    const TRIANGLE: [Vec3; 3] = [Vec3::ZERO, Vec3::X, Vec3::Y];
+
+    #[test]
+    fn rays_hit_triangles_from_both_sides() {
+        let front = ray_triangle_distance(Vec3::new(0.25, 0.25, 2.0), Vec3::NEG_Z, TRIANGLE);
+        assert_eq!(front, Some(2.0));
+        let back = ray_triangle_distance(Vec3::new(0.25, 0.25, -1.0), Vec3::Z * 2.0, TRIANGLE);
+        assert_eq!(back, Some(0.5));
+    }
+
+    #[test]
+    fn rays_miss_triangles_beside_behind_or_along_them() {
+        // Outside the hypotenuse.
+        assert_eq!(ray_triangle_distance(Vec3::new(0.75, 0.75, 2.0), Vec3::NEG_Z, TRIANGLE), None);
+        // Pointing away.
+        assert_eq!(ray_triangle_distance(Vec3::new(0.25, 0.25, 2.0), Vec3::Z, TRIANGLE), None);
+        // In the plane of the triangle.
+        assert_eq!(ray_triangle_distance(Vec3::new(-1.0, 0.25, 0.0), Vec3::X, TRIANGLE), None);
+    }
+
+    #[test]
+    fn casts_after_the_bvh_update_reuse_it() {
+        let mesh = Mesh::from(shape::Cube::default());
+        let mut instance_data = (0..8)
+            .map(|index| StandardInstanceData {
+                transform: Mat4::from_translation(Vec3::X * 2.0 * index as f32),
+                color: Vec4::ONE,
+            })
+            .collect::<InstanceDataVec<_>>();
+        instance_data.set(3, StandardInstanceData {
+            transform: Mat4::from_translation(Vec3::Y * 10.0),
+            color: Vec4::ONE,
+        });
+
+        // What `update_instance_bvhs` does in `CoreStage::PostUpdate`, the instances stay marked
+        // dirty until the next frame.
+        let mut bvh = InstanceBvh::default();
+        bvh.sync(&instance_data, mesh_radius(&mesh).unwrap());
+        assert!(!instance_data.dirty_ranges().is_empty());
+        assert!(matches!(synced_bvh(Some(&bvh), &instance_data, &mesh), Some(Cow::Borrowed(_))));
+
+        // Moved again later in the frame.
+        instance_data.set(3, StandardInstanceData {
+            transform: Mat4::from_translation(Vec3::Y * 20.0),
+            color: Vec4::ONE,
+        });
+        let synced = synced_bvh(Some(&bvh), &instance_data, &mesh).unwrap();
+        assert!(matches!(synced, Cow::Owned(_)));
+
+        let ray = Ray { origin: Vec3::new(0.0, 20.0, 10.0), direction: Vec3::NEG_Z };
+        let mut hits = Vec::new();
+        let transform = GlobalTransform::IDENTITY;
+        raycast_bvh(ray, Entity::from_raw(0), &transform, &synced, &instance_data, None, &mut hits);
+        assert_eq!(hits.iter().map(|hit| hit.instance_index).collect::<Vec<_>>(), vec![3]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_phase::{DrawFunctions, RenderPhase, SetItemPipeline},
+        render_resource::{
+            BindGroupLayout, PipelineCache, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipeline
+        },
+    },
+    pbr::{SetMeshBindGroup, SetShadowViewBindGroup, Shadow, ShadowPipeline, ShadowPipelineKey},
+};
+
+use crate::{
+    animated_by_texture, push_instance_input, push_instance_stream_layouts, DrawMeshInstanced,
+    ExtractedInstanceData, ExtractedInstanceLod, ImpostorLevel, InstanceData, InstancePipelineError,
+    InstancePipelineKey, InstanceStorageLayout, InstanceStreams, SetInstanceBindGroup, SpecializedInstancePipeline,
+    SpecializedInstancePipelines
+};
+
+/// Shadow vertex shader of [`StandardInstanceData`](crate::StandardInstanceData), which only
+/// reads its `transform`. It hard-codes that layout: locations 8 to 11 as a vertex buffer, or
+/// its `Instance` struct as a storage buffer, so other instance types need their own shader.
+pub const INSTANCED_DEPTH_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 5183932717318095403);
+
+pub type DrawInstancedShadowMesh = (
+    SetItemPipeline,
+    SetShadowViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetInstanceBindGroup<2>,
+    DrawMeshInstanced<true>,
+);
+
+/// bevy's shadow pipeline with the vertex shader of [`InstanceData::shadow_vertex_shader`] and
+/// the instance buffer of `D`.
+#[derive(Resource)]
+pub struct InstanceShadowPipeline<D: InstanceData> {
+    pub shadow_pipeline: ShadowPipeline,
+    pub vertex_shader: Option<Handle<Shader>>,
+    pub instance_storage_layout: Option<BindGroupLayout>,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> FromWorld for InstanceShadowPipeline<D> {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let shadow_pipeline = world.resource::<ShadowPipeline>();
+
+        InstanceShadowPipeline {
+            // `ShadowPipeline` isn't `Clone`.
+            shadow_pipeline: ShadowPipeline {
+                view_layout: shadow_pipeline.view_layout.clone(),
+                mesh_layout: shadow_pipeline.mesh_layout.clone(),
+                skinned_mesh_layout: shadow_pipeline.skinned_mesh_layout.clone(),
+                point_light_sampler: shadow_pipeline.point_light_sampler.clone(),
+                directional_light_sampler: shadow_pipeline.directional_light_sampler.clone(),
+            },
+            vertex_shader: match D::shadow_vertex_shader() {
+                ShaderRef::Default => None,
+                ShaderRef::Handle(handle) => Some(handle),
+                ShaderRef::Path(path) => Some(asset_server.load(path)),
+            },
+            instance_storage_layout: world.get_resource::<InstanceStorageLayout<D>>()
+                .map(|storage_layout| storage_layout.layout.clone()),
+            _data: Default::default(),
+        }
+    }
+}
+
+impl<D: InstanceData> SpecializedInstancePipeline for InstanceShadowPipeline<D> {
+    type Key = InstancePipelineKey<ShadowPipelineKey>;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, InstancePipelineError> {
+        let mut descriptor = self.shadow_pipeline.specialize(key.key, layout)?;
+        if let Some(vertex_shader) = &self.vertex_shader {
+            descriptor.vertex.shader = vertex_shader.clone();
+        }
+        push_instance_input::<D>(&mut descriptor, self.instance_storage_layout.as_ref())?;
+        push_instance_stream_layouts(&mut descriptor, &key.stream_layouts)?;
+        descriptor.label = Some("instanced_shadow_pipeline".into());
+        Ok(descriptor)
+    }
+}
+
+/// bevy's `queue_shadows` queues every visible shadow caster, instanced ones included, as a
+/// single mesh. Those items are switched over to the instanced shadow pipeline here, or dropped
+/// when `D` has no shadow vertex shader or the mesh is skinned with an animation texture, see
+/// [`animated_by_texture`]. The levels of an [`ExtractedInstanceLod`] are queued next to their
+/// entity.
+pub fn queue_instance_shadows<D: InstanceData>(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    instance_shadow_pipeline: Res<InstanceShadowPipeline<D>>,
+    mut pipelines: ResMut<SpecializedInstancePipelines<InstanceShadowPipeline<D>>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_meshes: Res<RenderAssets<Mesh>>,
+    instance_meshes: Query<
+        (&Handle<Mesh>, Option<&InstanceStreams>, Option<&ExtractedInstanceLod>),
+        With<ExtractedInstanceData<D>>,
+    >,
+    lod_meshes: Query<&Handle<Mesh>, Without<ImpostorLevel>>,
+    mut shadow_phases: Query<&mut RenderPhase<Shadow>>,
+) {
+    let draw_shadow_mesh = shadow_draw_functions.read().id::<DrawInstancedShadowMesh>();
+    let mut specialize = |mesh_handle: &Handle<Mesh>, instance_streams: Option<&InstanceStreams>| {
+        let mesh = render_meshes.get(mesh_handle)?;
+        if animated_by_texture::<D>(&mesh.layout) {
+            return None;
+        }
+        pipelines.specialize(
+            &pipeline_cache,
+            &instance_shadow_pipeline,
+            InstancePipelineKey::new(
+                ShadowPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                instance_streams,
+            ),
+            &mesh.layout,
+        )
+    };
+
+    for mut shadow_phase in &mut shadow_phases {
+        let mut lod_items = Vec::new();
+        shadow_phase.items.retain_mut(|item| {
+            let (mesh_handle, instance_streams, lod) = match instance_meshes.get(item.entity) {
+                Ok(instance_mesh) => instance_mesh,
+                Err(_) => return true,
+            };
+            if instance_shadow_pipeline.vertex_shader.is_none() {
+                return false;
+            }
+
+            for (level_entity, _) in lod.iter().flat_map(|lod| &lod.levels) {
+                let pipeline = lod_meshes.get(*level_entity).ok()
+                    .and_then(|mesh_handle| specialize(mesh_handle, instance_streams));
+                if let Some(pipeline) = pipeline {
+                    lod_items.push(Shadow {
+                        distance: item.distance,
+                        entity: *level_entity,
+                        pipeline,
+                        draw_function: draw_shadow_mesh,
+                    });
+                }
+            }
+
+            match specialize(mesh_handle, instance_streams) {
+                Some(id) => {
+                    item.pipeline = id;
+                    item.draw_function = draw_shadow_mesh;
+                    true
+                }
+                None => false,
+            }
+        });
+        for lod_item in lod_items {
+            shadow_phase.add(lod_item);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+struct Instance {
+    transform: mat4x4<f32>,
+    color: vec4<f32>,
+};
+
+fn instance_bounds(instance: Instance, mesh_radius: f32) -> vec4<f32> {
+    let scale = sqrt(max(
+        max(dot(instance.transform[0].xyz, instance.transform[0].xyz),
+            dot(instance.transform[1].xyz, instance.transform[1].xyz)),
+        dot(instance.transform[2].xyz, instance.transform[2].xyz)
+    ));
+    return vec4<f32>(instance.transform[3].xyz, mesh_radius * scale);
+}
@@ -0,0 +1,229 @@
+use bevy::{
+    prelude::*,
+    asset::load_internal_asset,
+    reflect::TypeUuid,
+    render::{
+        mesh::MeshVertexBufferLayout,
+        render_asset::RenderAssets,
+        render_resource::{
+            AsBindGroup, AsBindGroupError, BindGroupLayout, Face, PreparedBindGroup, RenderPipelineDescriptor,
+            ShaderRef, SpecializedMeshPipelineError
+        },
+        renderer::RenderDevice,
+        texture::FallbackImage,
+    },
+    pbr::{MaterialPipeline, MaterialPipelineKey, PBR_PREPASS_SHADER_HANDLE, PBR_SHADER_HANDLE},
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    InstanceData, InstanceMaterialPlugin, INSTANCED_DEPTH_SHADER_HANDLE, INSTANCED_IMPOSTOR_SHADER_HANDLE,
+    INSTANCED_PREPASS_SHADER_HANDLE
+};
+
+pub const INSTANCED_STANDARD_MATERIAL_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 12789694356422607846);
+
+/// Renders [`InstancedStandardMaterial`] entities with [`StandardInstanceData`] instances.
+#[derive(Default)]
+pub struct InstancedStandardMaterialPlugin {
+    /// See [`InstanceMaterialPlugin::prepass_enabled`].
+    pub prepass_enabled: bool,
+    /// See [`InstanceMaterialPlugin::storage_buffer`].
+    pub storage_buffer: bool,
+    /// See [`InstanceMaterialPlugin::picking`].
+    pub picking: bool,
+}
+
+impl Plugin for InstancedStandardMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            INSTANCED_STANDARD_MATERIAL_SHADER_HANDLE,
+            "instanced_standard_material.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_DEPTH_SHADER_HANDLE,
+            "instanced_depth.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_PREPASS_SHADER_HANDLE,
+            "instanced_prepass.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            INSTANCED_IMPOSTOR_SHADER_HANDLE,
+            "instanced_impostor.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugin(InstanceMaterialPlugin::<InstancedStandardMaterial, StandardInstanceData> {
+            prepass_enabled: self.prepass_enabled,
+            storage_buffer: self.storage_buffer,
+            picking: self.picking,
+            ..default()
+        });
+    }
+}
+
+/// [`InstanceData::instance_bounds_wgsl`] of [`StandardInstanceData`].
+pub const STANDARD_INSTANCE_BOUNDS_WGSL: &str = include_str!("standard_instance_bounds.wgsl");
+
+/// Default instance layout of [`InstancedStandardMaterial`].
+///
+/// `transform` is applied on top of the entity's [`GlobalTransform`], `color` (linear rgba) is
+/// multiplied with the base color of the material.
+///
+/// The instance attributes start at shader location 8, above every attribute of bevy's mesh pipeline.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable, InstanceData)]
+#[instance(
+    shadow_vertex_shader = INSTANCED_DEPTH_SHADER_HANDLE,
+    prepass_vertex_shader = INSTANCED_PREPASS_SHADER_HANDLE,
+    impostor_vertex_shader = INSTANCED_IMPOSTOR_SHADER_HANDLE,
+    instance_bounds_wgsl = STANDARD_INSTANCE_BOUNDS_WGSL,
+)]
+pub struct StandardInstanceData {
+    #[instance(location = 8, position)]
+    pub transform: Mat4,
+    pub color: Vec4,
+}
+
+impl StandardInstanceData {
+    pub fn new(transform: Transform, color: Color) -> Self {
+        StandardInstanceData {
+            transform: transform.compute_matrix(),
+            color: color.as_linear_rgba_f32().into(),
+        }
+    }
+}
+
+impl Default for StandardInstanceData {
+    fn default() -> Self {
+        StandardInstanceData {
+            transform: Mat4::IDENTITY,
+            color: Vec4::ONE,
+        }
+    }
+}
+
+impl From<Transform> for StandardInstanceData {
+    fn from(transform: Transform) -> Self {
+        StandardInstanceData::new(transform, Color::WHITE)
+    }
+}
+
+/// A [`StandardMaterial`] whose vertex shader places every instance with its [`StandardInstanceData`].
+///
+/// Lighting goes through the regular pbr fragment shader.
+#[derive(Clone, Debug, Default, Deref, DerefMut, TypeUuid)]
+#[uuid = "c7ffc35a-9b4a-44ed-9849-d64c96651a47"]
+pub struct InstancedStandardMaterial(pub StandardMaterial);
+
+impl From<StandardMaterial> for InstancedStandardMaterial {
+    fn from(material: StandardMaterial) -> Self {
+        InstancedStandardMaterial(material)
+    }
+}
+
+impl From<Color> for InstancedStandardMaterial {
+    fn from(color: Color) -> Self {
+        InstancedStandardMaterial(StandardMaterial::from(color))
+    }
+}
+
+// `StandardMaterialKey` is private, so the key is rebuilt from the wrapped material.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct InstancedStandardMaterialKey {
+    normal_map: bool,
+    cull_mode: Option<Face>,
+}
+
+impl From<&StandardMaterial> for InstancedStandardMaterialKey {
+    fn from(material: &StandardMaterial) -> Self {
+        InstancedStandardMaterialKey {
+            normal_map: material.normal_map_texture.is_some(),
+            cull_mode: material.cull_mode,
+        }
+    }
+}
+
+impl AsBindGroup for InstancedStandardMaterial {
+    type Data = InstancedStandardMaterialKey;
+
+    fn as_bind_group(
+        &self,
+        layout: &BindGroupLayout,
+        render_device: &RenderDevice,
+        images: &RenderAssets<Image>,
+        fallback_image: &FallbackImage,
+    ) -> Result<PreparedBindGroup<Self>, AsBindGroupError> {
+        let prepared = self.0.as_bind_group(layout, render_device, images, fallback_image)?;
+        Ok(PreparedBindGroup {
+            bindings: prepared.bindings,
+            bind_group: prepared.bind_group,
+            data: InstancedStandardMaterialKey::from(&self.0),
+        })
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        StandardMaterial::bind_group_layout(render_device)
+    }
+}
+
+impl Material for InstancedStandardMaterial {
+    fn vertex_shader() -> ShaderRef {
+        INSTANCED_STANDARD_MATERIAL_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        PBR_SHADER_HANDLE.typed().into()
+    }
+
+    fn prepass_fragment_shader() -> ShaderRef {
+        PBR_PREPASS_SHADER_HANDLE.typed().into()
+    }
+
+    #[inline]
+    fn alpha_mode(&self) -> AlphaMode {
+        self.0.alpha_mode
+    }
+
+    #[inline]
+    fn depth_bias(&self) -> f32 {
+        self.0.depth_bias
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // Prepass pipelines may have no fragment stage.
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.normal_map {
+                fragment.shader_defs.push(String::from("STANDARDMATERIAL_NORMAL_MAP"));
+            }
+            // Instance colors reach the pbr fragment shader through its vertex color input.
+            if !layout.contains(Mesh::ATTRIBUTE_COLOR) {
+                fragment.shader_defs.push(String::from("VERTEX_COLORS"));
+            }
+        }
+        if layout.contains(Mesh::ATTRIBUTE_COLOR) {
+            descriptor.vertex.shader_defs.push(String::from("MESH_VERTEX_COLORS"));
+        } else {
+            descriptor.vertex.shader_defs.push(String::from("VERTEX_COLORS"));
+        }
+        descriptor.primitive.cull_mode = key.bind_group_data.cull_mode;
+        if let Some(label) = &mut descriptor.label {
+            *label = format!("instanced_pbr_{}", *label).into();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    ecs::system::lifetimeless::Read,
+    render::{
+        render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+            BindGroupLayoutEntry, BindingType, BufferBindingType, BufferId, RenderPipelineDescriptor, ShaderStages
+        },
+        renderer::RenderDevice,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    push_instance_buffer_layout, CulledInstances, ExtractedInstanceLod, InstanceBuffer, InstanceBuffers, InstanceData,
+    InstancePipelineError
+};
+
+/// Layout of the bind group holding the instances of `D` as a storage buffer.
+///
+/// Only present when storage buffers are enabled for `D` and supported by the device, see
+/// [`InstanceMaterialPlugin::storage_buffer`](crate::InstanceMaterialPlugin::storage_buffer).
+#[derive(Resource)]
+pub struct InstanceStorageLayout<D: InstanceData> {
+    pub layout: BindGroupLayout,
+    _data: PhantomData<D>,
+}
+
+impl<D: InstanceData> FromWorld for InstanceStorageLayout<D> {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("instance_storage_layout"),
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        InstanceStorageLayout {
+            layout,
+            _data: Default::default(),
+        }
+    }
+}
+
+/// Whether storage buffers can be bound to the vertex stage, WebGL2 can't.
+pub fn storage_buffers_supported(render_device: &RenderDevice) -> bool {
+    render_device.limits().max_storage_buffers_per_shader_stage > 0
+}
+
+/// Adds the instances of `D` to `descriptor`.
+///
+/// With a `storage_layout` they are bound as the next bind group, and shaders are given the
+/// `INSTANCE_STORAGE_BUFFER` def to read them as `instances[instance_index]`. Otherwise they are
+/// bound as a vertex buffer, see [`push_instance_buffer_layout`].
+///
+/// The `Instance` struct of the shader must then have the exact layout of `D`. WGSL aligns
+/// `vec3` and `vec4` members to 16 bytes and rounds the size of the struct up to its alignment,
+/// so `D` needs explicit padding fields, e.g. after a `Vec3`, and a size which is a multiple of
+/// 16 bytes.
+pub fn push_instance_input<D: InstanceData>(
+    descriptor: &mut RenderPipelineDescriptor,
+    storage_layout: Option<&BindGroupLayout>,
+) -> Result<(), InstancePipelineError> {
+    let storage_layout = match storage_layout {
+        Some(storage_layout) => storage_layout,
+        None => return push_instance_buffer_layout::<D>(descriptor),
+    };
+    descriptor.layout.get_or_insert_with(Vec::new).push(storage_layout.clone());
+    let shader_def = "INSTANCE_STORAGE_BUFFER".to_string();
+    if let Some(fragment) = &mut descriptor.fragment {
+        fragment.shader_defs.push(shader_def.clone());
+    }
+    descriptor.vertex.shader_defs.push(shader_def);
+    Ok(())
+}
+
+/// Storage buffer bind groups of the instances of an entity: one for the whole
+/// [`InstanceBuffer`], and one for the [`ViewInstanceBuffers`](crate::ViewInstanceBuffers) of
+/// each view.
+#[derive(Component, Clone)]
+pub struct InstanceBindGroups {
+    pub shared: BindGroup,
+    pub views: HashMap<Entity, BindGroup>,
+}
+
+impl InstanceBindGroups {
+    /// The bind group to draw with in `view`.
+    pub fn get(&self, view: Entity) -> &BindGroup {
+        self.views.get(&view).unwrap_or(&self.shared)
+    }
+}
+
+/// Inserts the [`InstanceBindGroups`] of every entity with instances of `D`.
+///
+/// Bind groups are kept across frames, and only created again for buffers which were
+/// reallocated.
+pub fn queue_instance_bind_groups<D: InstanceData>(
+    mut commands: Commands,
+    mut bind_groups: Local<HashMap<BufferId, BindGroup>>,
+    storage_layout: Res<InstanceStorageLayout<D>>,
+    instance_buffers: Res<InstanceBuffers<D>>,
+    culled_instances: Res<CulledInstances<D>>,
+    lods: Query<&ExtractedInstanceLod>,
+    render_device: Res<RenderDevice>,
+) {
+    // Bind groups of buffers which aren't bound anymore are dropped with `previous`.
+    let mut previous = std::mem::take(&mut *bind_groups);
+    let mut bind_group = |instance_buffer: &InstanceBuffer| {
+        let id = instance_buffer.buffer.id();
+        let bind_group = previous.remove(&id)
+            .or_else(|| bind_groups.get(&id).cloned())
+            .unwrap_or_else(|| render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("instance_storage_bind_group"),
+                layout: &storage_layout.layout,
+                entries: &[BindGroupEntry {
+                    binding: 0,
+                    resource: instance_buffer.buffer.as_entire_binding(),
+                }],
+            }));
+        bind_groups.insert(id, bind_group.clone());
+        bind_group
+    };
+
+    for (entity, instance_buffer) in &instance_buffers.buffers {
+        let views = culled_instances.view_buffers(*entity)
+            .map(|(view, view_buffer)| (view, bind_group(view_buffer)))
+            .collect();
+        let shared = bind_group(instance_buffer);
+
+        let lod_levels = lods.get(*entity).into_iter().flat_map(|lod| &lod.levels).enumerate();
+        for (level, (level_entity, _)) in lod_levels {
+            let views = culled_instances.lod_view_buffers(*entity, level)
+                .map(|(view, view_buffer)| (view, bind_group(view_buffer)))
+                .collect();
+            // Level entities have a buffer in every view, `shared` is never bound.
+            commands.entity(*level_entity).insert(InstanceBindGroups {
+                shared: shared.clone(),
+                views,
+            });
+        }
+
+        commands.entity(*entity).insert(InstanceBindGroups { shared, views });
+    }
+}
+
+/// Binds the [`InstanceBindGroups`] of the item for the current view at index `I`. Does nothing
+/// for instances bound as a vertex buffer.
+pub struct SetInstanceBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetInstanceBindGroup<I> {
+    type Param = ();
+    type ViewWorldQuery = Entity;
+    type ItemWorldQuery = Option<Read<InstanceBindGroups>>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        view: Entity,
+        instance_bind_groups: Option<&'w InstanceBindGroups>,
+        _param: (),
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        if let Some(instance_bind_groups) = instance_bind_groups {
+            pass.set_bind_group(I, instance_bind_groups.get(view), &[]);
+        }
+        RenderCommandResult::Success
+    }
+}
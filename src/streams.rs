@@ -0,0 +1,157 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        render_resource::{RenderPipelineDescriptor, VertexBufferLayout},
+        renderer::{RenderDevice, RenderQueue},
+        RenderApp, RenderStage,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    clear_dirty_instances, push_vertex_buffer_layout, ExtractedInstanceData, InstanceBuffer, InstanceBuffers,
+    InstanceData, InstanceDataVec, InstancePipelineError
+};
+
+/// Adds `S` as an extra instance stream: entities with an [`InstanceDataVec<S>`] next to the
+/// instances of their material plugin get `S` bound as one more instance rate vertex buffer.
+///
+/// This lets data updated at different rates live in separate buffers, e.g. transforms uploaded
+/// once and colors updated every frame, so only the changed stream is uploaded again. Every
+/// stream of an entity must hold as many instances as its material instances, otherwise only as
+/// many instances as the shortest stream holds are drawn. The shader locations of `S` must not
+/// collide with the mesh or the other streams, the pipelines of the entity fail otherwise.
+///
+/// Entities with extra streams are always drawn with all of their instances: per view culling,
+/// sorting and filtering only reorder the material instances, and are skipped for them.
+pub struct InstanceStreamPlugin<S: InstanceData> {
+    pub _marker: PhantomData<S>,
+}
+
+impl<S: InstanceData> Default for InstanceStreamPlugin<S> {
+    fn default() -> Self {
+        InstanceStreamPlugin {
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<S: InstanceData> Plugin for InstanceStreamPlugin<S> {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<ExtractComponentPlugin<InstanceDataVec<S>>>() {
+            app.add_plugin(ExtractComponentPlugin::<InstanceDataVec<S>>::default())
+                .add_system_to_stage(CoreStage::First, clear_dirty_instances::<S>);
+        }
+
+        let render_app = app.sub_app_mut(RenderApp);
+        if !render_app.world.contains_resource::<PreparedInstanceStreams>() {
+            render_app
+                .init_resource::<PreparedInstanceStreams>()
+                .add_system_to_stage(
+                    RenderStage::Prepare,
+                    insert_instance_streams.after(InstanceStreamSystem::PrepareStreams),
+                );
+        }
+        render_app
+            .init_resource::<InstanceBuffers<S>>()
+            .add_system_to_stage(
+                RenderStage::Prepare,
+                prepare_instance_streams::<S>.label(InstanceStreamSystem::PrepareStreams),
+            );
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+pub enum InstanceStreamSystem {
+    PrepareStreams,
+}
+
+/// An extra instance buffer of an entity, see [`InstanceStreamPlugin`].
+#[derive(Clone)]
+pub struct InstanceStream {
+    pub label: &'static str,
+    pub layout: VertexBufferLayout,
+    pub buffer: InstanceBuffer,
+}
+
+/// The extra instance streams of an entity, ordered by label. They are bound to the vertex buffer
+/// slots following the instances of the material.
+#[derive(Component, Clone, Default)]
+pub struct InstanceStreams(pub Vec<InstanceStream>);
+
+impl InstanceStreams {
+    /// Vertex buffer layouts of the streams, part of the pipeline key of the entity.
+    pub fn layouts(&self) -> Vec<VertexBufferLayout> {
+        self.0.iter().map(|stream| stream.layout.clone()).collect()
+    }
+}
+
+/// Streams of every entity prepared this frame, gathered here since each stream type is prepared
+/// by its own system.
+#[derive(Resource, Default)]
+pub struct PreparedInstanceStreams(HashMap<Entity, Vec<InstanceStream>>);
+
+pub fn prepare_instance_streams<S: InstanceData>(
+    query: Query<(Entity, &ExtractedInstanceData<S>)>,
+    mut instance_buffers: ResMut<InstanceBuffers<S>>,
+    mut prepared_streams: ResMut<PreparedInstanceStreams>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    instance_buffers.prepare(&query, &render_device, &render_queue);
+    for (entity, instance_buffer) in &instance_buffers.buffers {
+        prepared_streams.0.entry(*entity).or_default().push(InstanceStream {
+            label: S::buffer_label(),
+            layout: S::buffer_layout(),
+            buffer: instance_buffer.clone(),
+        });
+    }
+}
+
+pub fn insert_instance_streams(
+    mut commands: Commands,
+    mut prepared_streams: ResMut<PreparedInstanceStreams>,
+) {
+    for (entity, mut streams) in prepared_streams.0.drain() {
+        // Streams are prepared in no particular order, the slots of an entity must be stable.
+        streams.sort_unstable_by_key(|stream| stream.label);
+        commands.entity(entity).insert(InstanceStreams(streams));
+    }
+}
+
+/// Key of the instanced pipelines: the key of the wrapped bevy pipeline, and the layouts of the
+/// [`InstanceStreams`] of the entity.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct InstancePipelineKey<K> {
+    pub key: K,
+    pub stream_layouts: Vec<VertexBufferLayout>,
+    /// Picking variant of the pipeline, see [`use_instance_picking`](crate::use_instance_picking).
+    pub picking: bool,
+}
+
+impl<K> InstancePipelineKey<K> {
+    pub fn new(key: K, streams: Option<&InstanceStreams>) -> Self {
+        InstancePipelineKey {
+            key,
+            stream_layouts: streams.map(InstanceStreams::layouts).unwrap_or_default(),
+            picking: false,
+        }
+    }
+}
+
+/// Appends the layouts of the extra instance streams after the vertex buffers of `descriptor`.
+///
+/// Fails like [`push_instance_buffer_layout`](crate::push_instance_buffer_layout) if a stream
+/// uses a shader location of the mesh, the instances or another stream.
+pub fn push_instance_stream_layouts(
+    descriptor: &mut RenderPipelineDescriptor,
+    stream_layouts: &[VertexBufferLayout],
+) -> Result<(), InstancePipelineError> {
+    for stream_layout in stream_layouts {
+        push_vertex_buffer_layout(descriptor, stream_layout.clone(), "instance stream")?;
+    }
+    Ok(())
+}